@@ -63,6 +63,17 @@ const Page = {
             }
         };
 
+        const forget = (gameId) => {
+            delete this.chats[gameId];
+            delete this.metas[gameId];
+            if (this.activeChat === gameId) {
+                this.activeChat = Object.keys(this.chats)[0] || null;
+            }
+        };
+        this.events.onleft = forget;
+        this.events.onkicked = forget;
+        this.events.onclosed = forget;
+
         window.onhashchange = async () => {
             let join_hash = window.location.hash.match(/#join:([0-9a-f-]+)$/);
             if (join_hash) {
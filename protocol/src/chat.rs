@@ -0,0 +1,23 @@
+use serde::{Deserialize, Serialize};
+
+use crate::player::PlayerId;
+
+/// A single line in the built-in lobby chat
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ChatLine {
+    /// Message written by a player
+    Message {
+        sender: PlayerId,
+        text: String,
+    },
+    /// Server-issued system lines
+    Joined(PlayerId),
+    Left(PlayerId),
+    Kicked(PlayerId),
+    Muted(PlayerId),
+    Unmuted(PlayerId),
+}
+impl ChatLine {
+    /// Maximum length of a player-written message, in characters
+    pub const MAX_MESSAGE_LEN: usize = 500;
+}
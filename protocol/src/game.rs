@@ -1,6 +1,10 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::player::PlayerId;
+
 /// Game lobby (including running games)
 #[derive(
     Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Deserialize, Serialize,
@@ -12,3 +16,107 @@ impl GameId {
         Self(Uuid::new_v4())
     }
 }
+
+/// Lifecycle phase of a lobby
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize, Serialize)]
+pub enum LobbyPhase {
+    /// Gathering players, not started yet
+    #[default]
+    Waiting,
+    /// Start requested, waiting for all players to confirm
+    ReadyCheck,
+    Running,
+    Finished,
+}
+
+/// Outcome of a finished game, as reported by the game itself
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct GameResult {
+    /// Players grouped by placement, best first. Players in the same group are tied.
+    pub placements: Vec<Vec<PlayerId>>,
+    pub scores: HashMap<PlayerId, f64>,
+    pub winners: Vec<PlayerId>,
+    /// Game-specific details
+    pub data: serde_json::Value,
+}
+
+/// A stored game result
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MatchRecord {
+    pub game_id: GameId,
+    /// Game mode name
+    pub mode: String,
+    /// Unix timestamp in seconds
+    pub finished_at: u64,
+    /// Lobby members when the game finished
+    pub players: Vec<PlayerId>,
+    pub result: GameResult,
+}
+
+/// Turn order and timers of a turn-based game
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TurnInfo {
+    pub seats: Vec<PlayerId>,
+    pub current: Option<PlayerId>,
+    /// Time left in the current turn, if limited
+    pub turn_remaining_ms: Option<u64>,
+    /// Remaining chess clock time per player
+    pub clocks_ms: HashMap<PlayerId, u64>,
+}
+
+/// Commit-reveal of the random seed of a game, for verifying fairness
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SeedCommitment {
+    /// Hex SHA-256 of the seed, published when the game starts
+    pub hash: String,
+    /// Hex seed, revealed when the game has finished
+    pub seed: Option<String>,
+}
+
+/// Team of a lobby, as configured by the game mode
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TeamInfo {
+    pub name: String,
+    /// Maximum number of members, if any
+    pub capacity: Option<usize>,
+}
+impl TeamInfo {
+    pub fn new(name: &str, capacity: Option<usize>) -> Self {
+        Self {
+            name: name.to_owned(),
+            capacity,
+        }
+    }
+}
+
+/// Matchmaking preferences of a queued player
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct MatchParams {
+    /// Skill rating to match by. Unrated players match anyone.
+    pub rating: Option<u32>,
+}
+
+/// Skill rating of a player in a game mode
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Rating {
+    pub rating: f64,
+    /// Glicko-2 rating deviation
+    pub deviation: f64,
+    /// Glicko-2 volatility
+    pub volatility: f64,
+    /// Number of rated games played
+    pub games: u32,
+}
+impl Rating {
+    pub const INITIAL: f64 = 1500.0;
+}
+impl Default for Rating {
+    fn default() -> Self {
+        Self {
+            rating: Self::INITIAL,
+            deviation: 350.0,
+            volatility: 0.06,
+            games: 0,
+        }
+    }
+}
@@ -1,5 +1,6 @@
+mod chat;
 mod game;
 mod message;
 mod player;
 
-pub use self::{game::*, message::*, player::*};
+pub use self::{chat::*, game::*, message::*, player::*};
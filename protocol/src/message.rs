@@ -1,7 +1,15 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::{game::GameId, player::PlayerId, Identity};
+use crate::{
+    chat::ChatLine,
+    game::{
+        GameId, GameResult, LobbyPhase, MatchParams, MatchRecord, SeedCommitment, TeamInfo,
+        TurnInfo,
+    },
+    player::{PlayerId, PlayerInfo, Profile, ProfileError},
+    Identity,
+};
 
 /// Message id, used to match replies to requests
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
@@ -35,8 +43,50 @@ pub enum ClientMessageData {
     CreateGame(String),
     JoinGame(GameId),
     LeaveGame(GameId),
-    KickPlayer(GameId, PlayerId),
+    /// Wait to be matched with other players into a new lobby of a game mode
+    QueueForMatch {
+        mode: String,
+        params: MatchParams,
+    },
+    /// Leave the matchmaking queue
+    LeaveQueue,
+    /// Remove a player from the lobby, with an optional reason shown to them. Leader only.
+    KickPlayer(GameId, PlayerId, Option<String>),
+    /// Hand the leadership over to another member. Leader only.
     PromoteLeader(GameId, PlayerId),
+    /// Remove all members and delete the lobby, abandoning a running game
+    /// without a result. Leader only.
+    CloseGame(GameId),
+    /// Fill a seat with a bot of the game mode. Leader only.
+    AddBot(GameId),
+    /// Leader only
+    RemoveBot(GameId, PlayerId),
+    /// Switch to a team by name
+    JoinTeam(GameId, String),
+    LeaveTeam(GameId),
+    /// Move a player to a team, or out of all teams with `None`. Leader only.
+    AssignTeam(GameId, PlayerId, Option<String>),
+    /// Start the game, or begin the ready check if the mode requires one. Leader only.
+    StartGame(GameId),
+    /// Confirm readiness during a ready check
+    SetReady(GameId, bool),
+    /// Switch the lobby to another game mode, keeping the members. Leader only.
+    ChangeGameMode(GameId, String),
+    /// Get the recorded input log of a lobby for bug reports. Leader only. Not
+    /// available while a game with commit-reveal is running.
+    ExportGameLog(GameId),
+    /// List own past games, most recent first
+    MatchHistory,
+    /// Set own profile, visible in all joined lobbies
+    SetProfile(Profile),
+
+    /// Send a message to the lobby chat
+    SendChat(GameId, String),
+    /// Get the retained lobby chat history
+    ChatHistory(GameId),
+    /// Prevent a player from chatting. Leader only.
+    MuteChat(GameId, PlayerId),
+    UnmuteChat(GameId, PlayerId),
 
     /// When connecting for the first time, identify as a new player
     NewIdentity,
@@ -44,10 +94,16 @@ pub enum ClientMessageData {
     /// When reconnecting, identify as a player
     Identify(Identity),
 
+    /// Identify as an externally authenticated player using a token
+    Authenticate(String),
+
     /// Game-specific message
     Inner(GameId, serde_json::Value),
 }
 impl ClientMessageData {
+    /// Maximum length of a kick reason, in characters
+    pub const MAX_KICK_REASON_LEN: usize = 200;
+
     pub fn finalize(self) -> ClientMessage {
         ClientMessage {
             id: MessageId::new(),
@@ -56,7 +112,7 @@ impl ClientMessageData {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum ServerMessage {
     /// Server-initiated message
     ServerSent(ServerSentMessage),
@@ -64,17 +120,84 @@ pub enum ServerMessage {
     ReplyTo(MessageId, ReplyMessage),
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Lobby state as seen by one player
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GameInfo {
+    pub id: GameId,
+    pub leader: PlayerId,
+    pub phase: LobbyPhase,
+    pub players: Vec<PlayerInfo>,
+    pub public_state: serde_json::Value,
+    pub private_state: serde_json::Value,
+    /// Name of the current game mode
+    pub mode: String,
+    /// Turn order, if the game is turn-based
+    pub turn: Option<TurnInfo>,
+    /// Current tick number, if the lobby runs a tick loop
+    pub tick: Option<u64>,
+    /// Seed commitment, if the game mode uses commit-reveal
+    pub seed: Option<SeedCommitment>,
+    /// Teams of the game mode, empty if it has none
+    pub teams: Vec<TeamInfo>,
+    /// Private state shared with the own team
+    pub team_state: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum ServerSentMessage {
     Error {
         message: String,
     },
-    GameInfo {
+    /// Lobby state, sent whenever it changes
+    GameInfo(Box<GameInfo>),
+    /// A game has finished with this result
+    GameResult {
+        id: GameId,
+        result: GameResult,
+    },
+    /// New line in the lobby chat
+    Chat {
+        id: GameId,
+        line: ChatLine,
+    },
+    /// One-off game-specific notification
+    GameEvent {
+        id: GameId,
+        payload: serde_json::Value,
+    },
+    /// You were added to a lobby
+    Joined {
+        id: GameId,
+    },
+    /// You left a lobby
+    Left {
+        id: GameId,
+    },
+    /// The leader removed you from a lobby
+    Kicked {
+        id: GameId,
+        reason: Option<String>,
+    },
+    /// You became the leader of a lobby
+    Promoted {
         id: GameId,
-        leader: PlayerId,
-        players: Vec<PlayerId>,
-        public_state: serde_json::Value,
-        private_state: serde_json::Value,
+    },
+    /// The lobby was deleted and all members removed
+    LobbyClosed {
+        id: GameId,
+    },
+    /// Place in the matchmaking queue, sent whenever it changes
+    QueuePosition {
+        mode: String,
+        /// Starting from 1
+        position: usize,
+        /// Number of players queued for the mode
+        queued: usize,
+    },
+    /// Matchmaking created a lobby and joined you to it
+    MatchFound {
+        id: GameId,
+        mode: String,
     },
 }
 impl ServerSentMessage {
@@ -83,7 +206,7 @@ impl ServerSentMessage {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum ReplyMessage {
     /// Operation was successful, no data to return
     Ok,
@@ -91,9 +214,14 @@ pub enum ReplyMessage {
     Identity(Identity),
     GameCreated(GameId),
     JoinedToGame(GameId),
+    BotAdded(PlayerId),
     Error(ErrorReply),
     GameModes(Vec<String>),
     JoinedGames(Vec<GameId>),
+    ChatHistory(Vec<ChatLine>),
+    MatchHistory(Vec<MatchRecord>),
+    /// Recorded input log, see `wgfw::replay::GameLog`
+    GameLog(serde_json::Value),
     /// Reply to a game-specific message
     Inner(serde_json::Value),
 }
@@ -104,7 +232,7 @@ impl ReplyMessage {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum ErrorReply {
     AlreadyIdentified,
     MustIdentifyFirst,
@@ -112,6 +240,39 @@ pub enum ErrorReply {
     NoSuchGameLobby,
     NotInThatGame,
     InvalidReconnectionSecret,
+    /// The server doesn't have an authenticator configured
+    AuthenticationNotSupported,
+    InvalidAuthenticationToken,
+    InvalidProfile(ProfileError),
+    /// Only the lobby leader can do that
+    NotLeader,
+    /// The target player is not in that game
+    NoSuchPlayer,
+    /// The current game has disabled the lobby chat
+    ChatDisabled,
+    MutedInChat,
+    ChatMessageTooLong,
+    KickReasonTooLong,
+    NoSuchTeam,
+    /// The game mode has no bot
+    BotsNotAvailable,
+    /// The target player is not a bot
+    NotABot,
+    /// Bots can't be lobby leaders
+    BotCannotLead,
+    AlreadyQueued,
+    NotQueued,
+    TeamFull,
+    /// Not possible in the current lobby phase
+    WrongPhase,
+    NotEnoughPlayers,
+    TooManyPlayers,
+    /// The game doesn't accept new players at the moment
+    CannotJoin,
+    /// The server doesn't store match history
+    MatchHistoryNotAvailable,
+    /// The lobby isn't being recorded
+    NotRecorded,
     /// Game-specific error message
     Inner(serde_json::Value),
 }
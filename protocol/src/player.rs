@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::game::Rating;
+
 /// Player, independent of browser session
 #[derive(
     Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Deserialize, Serialize,
@@ -11,6 +13,11 @@ impl PlayerId {
     pub fn new() -> Self {
         Self(Uuid::new_v4())
     }
+
+    /// Stable id for an externally authenticated account
+    pub fn from_subject(subject: &str) -> Self {
+        Self(Uuid::new_v5(&Uuid::NAMESPACE_URL, subject.as_bytes()))
+    }
 }
 
 /// A secret reconnection token, used to identify a player when reconnecting
@@ -42,3 +49,138 @@ impl Identity {
         self.reconnection_secret.verify(key, self.player_id)
     }
 }
+
+/// Server-level player profile, shared by all lobbies
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Profile {
+    pub display_name: String,
+    /// Client-defined avatar identifier
+    pub avatar: Option<String>,
+    /// Color as `#rrggbb`
+    pub color: Option<String>,
+}
+impl Profile {
+    pub const MAX_DISPLAY_NAME_LEN: usize = 32;
+    pub const MAX_AVATAR_LEN: usize = 64;
+
+    pub fn validate(&self) -> Result<(), ProfileError> {
+        let name_len = self.display_name.chars().count();
+        if self.display_name.trim().is_empty() || name_len > Self::MAX_DISPLAY_NAME_LEN {
+            return Err(ProfileError::DisplayNameLength);
+        }
+        if self.display_name.chars().any(char::is_control) {
+            return Err(ProfileError::DisplayNameCharacters);
+        }
+
+        if let Some(avatar) = &self.avatar {
+            if avatar.is_empty()
+                || avatar.len() > Self::MAX_AVATAR_LEN
+                || !avatar
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+            {
+                return Err(ProfileError::InvalidAvatar);
+            }
+        }
+
+        if let Some(color) = &self.color {
+            let hex = color.strip_prefix('#').unwrap_or_default();
+            if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(ProfileError::InvalidColor);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ProfileError {
+    DisplayNameLength,
+    DisplayNameCharacters,
+    InvalidAvatar,
+    InvalidColor,
+}
+
+/// Lobby member, as seen by clients
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PlayerInfo {
+    pub id: PlayerId,
+    /// `None` if the player hasn't set a profile
+    pub profile: Option<Profile>,
+    /// Confirmed readiness during a ready check
+    pub ready: bool,
+    /// Name of the team, if in one
+    pub team: Option<String>,
+    /// Server-side bot
+    pub bot: bool,
+    /// Rating in the current game mode, if it's rated
+    pub rating: Option<Rating>,
+    /// Currently connected to the server
+    pub connected: bool,
+    /// Unix timestamp in seconds of the disconnect, `None` while connected
+    pub last_seen: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named(display_name: &str) -> Profile {
+        Profile {
+            display_name: display_name.to_owned(),
+            avatar: None,
+            color: None,
+        }
+    }
+
+    #[test]
+    fn display_names_are_limited_in_characters() {
+        let longest = "é".repeat(Profile::MAX_DISPLAY_NAME_LEN);
+        assert_eq!(named(&longest).validate(), Ok(()));
+        let too_long = "é".repeat(Profile::MAX_DISPLAY_NAME_LEN + 1);
+        assert_eq!(
+            named(&too_long).validate(),
+            Err(ProfileError::DisplayNameLength)
+        );
+        assert_eq!(
+            named(" \t").validate(),
+            Err(ProfileError::DisplayNameLength)
+        );
+    }
+
+    #[test]
+    fn display_names_reject_control_characters() {
+        for name in ["new\nline", "bell\u{7}", "del\u{7f}", "c1\u{85}"] {
+            assert_eq!(
+                named(name).validate(),
+                Err(ProfileError::DisplayNameCharacters)
+            );
+        }
+    }
+
+    #[test]
+    fn avatars_and_colors_are_checked() {
+        let valid = Profile {
+            avatar: Some("robot-2.png".to_owned()),
+            color: Some("#1a2B3c".to_owned()),
+            ..named("player")
+        };
+        assert_eq!(valid.validate(), Ok(()));
+
+        for avatar in ["", "a b", &"a".repeat(Profile::MAX_AVATAR_LEN + 1)] {
+            let profile = Profile {
+                avatar: Some(avatar.to_owned()),
+                ..named("player")
+            };
+            assert_eq!(profile.validate(), Err(ProfileError::InvalidAvatar));
+        }
+        for color in ["1a2b3c", "#1a2b3", "#1a2b3g"] {
+            let profile = Profile {
+                color: Some(color.to_owned()),
+                ..named("player")
+            };
+            assert_eq!(profile.validate(), Err(ProfileError::InvalidColor));
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! External authentication of player identities
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use orion::hazardous::mac::hmac::sha256::{HmacSha256, SecretKey, Tag};
+use serde::{Deserialize, Serialize};
+
+use wgfw_protocol::PlayerId;
+
+/// Verified claims of an authenticated player
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    /// Stable account identifier, i.e. the `sub` claim
+    pub subject: String,
+    /// All claims in the token, including the standard ones
+    pub raw: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// The token could not be parsed
+    Malformed,
+    /// Unsupported signing algorithm
+    UnsupportedAlgorithm,
+    /// Signature doesn't match
+    InvalidSignature,
+    /// The token has expired or isn't valid yet
+    Expired,
+    /// The token doesn't contain a subject
+    MissingSubject,
+}
+
+/// Verifies tokens sent with `ClientMessageData::Authenticate`
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, token: &str) -> Result<Claims, AuthError>;
+
+    /// Map verified claims to a player. The same account must always get the same id.
+    fn player_id(&self, claims: &Claims) -> PlayerId {
+        PlayerId::from_subject(&claims.subject)
+    }
+}
+
+/// Verifies HS256-signed JWTs using a locally configured key
+pub struct JwtHmacAuthenticator {
+    key: SecretKey,
+}
+impl JwtHmacAuthenticator {
+    /// HS256 keys must have at least 256 bits, see RFC 7518
+    pub const MIN_KEY_LEN: usize = 32;
+
+    pub fn new(key: &[u8]) -> Result<Self, InvalidKey> {
+        if key.len() < Self::MIN_KEY_LEN {
+            return Err(InvalidKey);
+        }
+        let key = SecretKey::from_slice(key).map_err(|_| InvalidKey)?;
+        Ok(Self { key })
+    }
+}
+
+/// The HMAC key is too short, see [`JwtHmacAuthenticator::MIN_KEY_LEN`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidKey;
+impl fmt::Display for InvalidKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "HMAC key must have at least {} bytes",
+            JwtHmacAuthenticator::MIN_KEY_LEN
+        )
+    }
+}
+impl std::error::Error for InvalidKey {}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+}
+
+fn decode_part<T: serde::de::DeserializeOwned>(part: &str) -> Result<T, AuthError> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| AuthError::Malformed)?;
+    serde_json::from_slice(&bytes).map_err(|_| AuthError::Malformed)
+}
+
+impl Authenticator for JwtHmacAuthenticator {
+    fn authenticate(&self, token: &str) -> Result<Claims, AuthError> {
+        let (signed, signature) = token.rsplit_once('.').ok_or(AuthError::Malformed)?;
+        let (header, payload) = signed.split_once('.').ok_or(AuthError::Malformed)?;
+
+        let header: JwtHeader = decode_part(header)?;
+        if header.alg != "HS256" {
+            return Err(AuthError::UnsupportedAlgorithm);
+        }
+
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| AuthError::Malformed)?;
+        let tag = Tag::from_slice(&signature).map_err(|_| AuthError::InvalidSignature)?;
+        HmacSha256::verify(&tag, &self.key, signed.as_bytes())
+            .map_err(|_| AuthError::InvalidSignature)?;
+
+        let raw: serde_json::Map<String, serde_json::Value> = decode_part(payload)?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System clock before UNIX epoch")
+            .as_secs();
+        if let Some(exp) = raw.get("exp") {
+            if exp.as_u64().ok_or(AuthError::Malformed)? <= now {
+                return Err(AuthError::Expired);
+            }
+        }
+        if let Some(nbf) = raw.get("nbf") {
+            if nbf.as_u64().ok_or(AuthError::Malformed)? > now {
+                return Err(AuthError::Expired);
+            }
+        }
+
+        let subject = raw
+            .get("sub")
+            .and_then(|sub| sub.as_str())
+            .ok_or(AuthError::MissingSubject)?
+            .to_owned();
+
+        Ok(Claims { subject, raw })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const KEY: &[u8] = b"key of the tests, 32 bytes long.";
+
+    fn token(key: &[u8], alg: &str, claims: serde_json::Value) -> String {
+        let header = URL_SAFE_NO_PAD.encode(json!({ "alg": alg, "typ": "JWT" }).to_string());
+        let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let signed = format!("{header}.{payload}");
+        let key = SecretKey::from_slice(key).unwrap();
+        let tag = HmacSha256::hmac(&key, signed.as_bytes()).unwrap();
+        format!(
+            "{signed}.{}",
+            URL_SAFE_NO_PAD.encode(tag.unprotected_as_bytes())
+        )
+    }
+
+    #[test]
+    fn accepts_valid_tokens() {
+        let authenticator = JwtHmacAuthenticator::new(KEY).unwrap();
+        let claims = json!({ "sub": "alice", "exp": u32::MAX });
+        let claims = authenticator
+            .authenticate(&token(KEY, "HS256", claims))
+            .unwrap();
+        assert_eq!(claims.subject, "alice");
+    }
+
+    #[test]
+    fn rejects_invalid_tokens() {
+        let authenticator = JwtHmacAuthenticator::new(KEY).unwrap();
+        let claims = json!({ "sub": "alice" });
+        let cases = [
+            (
+                token(KEY, "HS256", json!({ "sub": "alice", "exp": 1 })),
+                AuthError::Expired,
+            ),
+            (
+                token(KEY, "HS256", json!({ "sub": "alice", "nbf": u32::MAX })),
+                AuthError::Expired,
+            ),
+            (
+                token(b"another key, also 32 bytes long.", "HS256", claims.clone()),
+                AuthError::InvalidSignature,
+            ),
+            (
+                token(KEY, "HS512", claims.clone()),
+                AuthError::UnsupportedAlgorithm,
+            ),
+            (token(KEY, "none", claims), AuthError::UnsupportedAlgorithm),
+            (token(KEY, "HS256", json!({})), AuthError::MissingSubject),
+            ("not a token".to_owned(), AuthError::Malformed),
+        ];
+        for (token, error) in cases {
+            assert_eq!(authenticator.authenticate(&token).err(), Some(error));
+        }
+    }
+
+    #[test]
+    fn rejects_short_keys() {
+        assert!(JwtHmacAuthenticator::new(&KEY[1..]).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+//! Server-side bot players
+//!
+//! Register a [`Bot`] for a game mode with
+//! [`Builder::register_bot`](crate::Builder::register_bot), and lobby leaders
+//! can fill seats with it. Bots are lobby members like any other player: they
+//! see the same state a human would, and act by sending messages through
+//! [`Game::on_message_from`](crate::game_state::Game::on_message_from).
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use wgfw_protocol::{GameId, LobbyPhase, PlayerId, PlayerInfo, Profile, TurnInfo};
+
+use crate::game_state::{EventId, Lobby, Updates};
+
+/// Game state as seen by a bot
+#[derive(Debug, Clone)]
+pub struct BotView {
+    pub game_id: GameId,
+    /// The bot itself
+    pub me: PlayerId,
+    pub leader: PlayerId,
+    pub phase: LobbyPhase,
+    pub players: Vec<PlayerInfo>,
+    pub public_state: serde_json::Value,
+    pub private_state: serde_json::Value,
+    pub team_state: serde_json::Value,
+    pub turn: Option<TurnInfo>,
+}
+impl BotView {
+    /// `lobby` as seen by `me`, with the parts shared by all members
+    pub(crate) fn new(
+        game_id: GameId,
+        lobby: &Lobby,
+        me: PlayerId,
+        players: Vec<PlayerInfo>,
+        public_state: serde_json::Value,
+    ) -> Self {
+        Self {
+            game_id,
+            me,
+            leader: lobby.common.leader,
+            phase: lobby.common.phase,
+            players,
+            public_state,
+            private_state: lobby.state_for_player(me),
+            team_state: lobby.state_for_team_of(me),
+            turn: lobby.turn_info(),
+        }
+    }
+}
+
+pub trait Bot: Send + Sync {
+    /// The game state has changed and the think delay has passed. Return a
+    /// game-specific message to send, or `None` to wait for the next change.
+    fn decide(&mut self, view: &BotView) -> Option<serde_json::Value>;
+
+    /// Reply to the last message sent
+    fn on_reply(&mut self, _reply: Result<serde_json::Value, serde_json::Value>) {}
+
+    /// Profile shown to other players
+    fn profile(&self) -> Option<Profile> {
+        None
+    }
+}
+
+type Constructor = Box<dyn Fn() -> Box<dyn Bot> + Send + Sync>;
+
+/// Bot of a game mode
+pub struct BotMode {
+    pub constructor: Constructor,
+    /// Time between a state change and the bot acting on it
+    pub think_delay: Duration,
+}
+
+/// Bot in a lobby
+pub(crate) struct BotPlayer {
+    pub game_id: GameId,
+    pub bot: Box<dyn Bot>,
+    pub think_delay: Duration,
+    /// Hash of the last state seen
+    pub last_state: Option<u64>,
+    /// Latest state, not acted on yet
+    pub view: Option<BotView>,
+    /// Scheduled turn, while waiting for the think delay
+    pub wakeup: Option<EventId>,
+}
+impl BotPlayer {
+    pub fn new(game_id: GameId, bot: Box<dyn Bot>, think_delay: Duration) -> Self {
+        Self {
+            game_id,
+            bot,
+            think_delay,
+            last_state: None,
+            view: None,
+            wakeup: None,
+        }
+    }
+
+    /// Keep the latest state. Returns the turn to schedule, unless one already is.
+    pub fn observe(&mut self, view: BotView, now: Instant) -> Option<(EventId, Instant)> {
+        self.view = Some(view);
+        if self.wakeup.is_some() {
+            return None;
+        }
+        let wakeup = EventId::new();
+        self.wakeup = Some(wakeup);
+        Some((wakeup, now + self.think_delay))
+    }
+
+    /// Act on the latest state, if the bot is still a member of `lobby`
+    pub fn take_turn(&mut self, lobby: &mut Lobby, bot_id: PlayerId) -> Updates {
+        self.wakeup = None;
+        let Some(view) = self.view.take() else {
+            return Updates::NONE;
+        };
+        let Some(message) = self.bot.decide(&view) else {
+            return Updates::NONE;
+        };
+        if !lobby.common.players.contains(&bot_id) {
+            return Updates::NONE;
+        }
+
+        let (updates, reply) = lobby.on_message_from(bot_id, message);
+        self.bot.on_reply(reply);
+        updates
+    }
+}
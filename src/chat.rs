@@ -0,0 +1,47 @@
+//! Built-in lobby chat
+
+use std::collections::{HashSet, VecDeque};
+
+use wgfw_protocol::{ChatLine, PlayerId};
+
+pub const DEFAULT_HISTORY_LIMIT: usize = 100;
+
+/// Chat channel of a single lobby
+#[derive(Debug)]
+pub struct LobbyChat {
+    history: VecDeque<ChatLine>,
+    /// Number of lines to retain
+    limit: usize,
+    /// Players that the leader has muted
+    pub muted: HashSet<PlayerId>,
+}
+impl Default for LobbyChat {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_LIMIT)
+    }
+}
+impl LobbyChat {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            history: VecDeque::new(),
+            limit,
+            muted: HashSet::new(),
+        }
+    }
+
+    /// Retained lines, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &ChatLine> {
+        self.history.iter()
+    }
+
+    pub fn is_muted(&self, player: &PlayerId) -> bool {
+        self.muted.contains(player)
+    }
+
+    pub(crate) fn push(&mut self, line: ChatLine) {
+        self.history.push_back(line);
+        while self.history.len() > self.limit {
+            self.history.pop_front();
+        }
+    }
+}
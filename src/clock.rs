@@ -0,0 +1,169 @@
+//! Time source for the server and games
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+pub trait Clock: Send + Sync + fmt::Debug {
+    fn now(&self) -> Instant;
+    /// Resolves once the clock has reached `at`
+    fn sleep_until(&self, at: Instant) -> Sleep;
+
+    /// Wall-clock time as a Unix timestamp in seconds
+    fn unix_time(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System clock before UNIX epoch")
+            .as_secs()
+    }
+}
+
+/// Real time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, at: Instant) -> Sleep {
+        Box::pin(tokio::time::sleep_until(at))
+    }
+}
+
+/// Clock that only moves when advanced, for deterministic tests and
+/// simulations. Its Unix time counts from zero at creation.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    inner: Arc<ManualClockInner>,
+}
+
+#[derive(Debug)]
+struct ManualClockInner {
+    created: Instant,
+    now: Mutex<Instant>,
+    changed: Notify,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        let created = Instant::now();
+        Self {
+            inner: Arc::new(ManualClockInner {
+                created,
+                now: Mutex::new(created),
+                changed: Notify::new(),
+            }),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.inner.now.lock().unwrap() += by;
+        self.inner.changed.notify_waiters();
+    }
+
+    /// Move the clock forward to `at`. Never moves backwards.
+    pub fn advance_to(&self, at: Instant) {
+        {
+            let mut now = self.inner.now.lock().unwrap();
+            *now = (*now).max(at);
+        }
+        self.inner.changed.notify_waiters();
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.inner.now.lock().unwrap()
+    }
+
+    fn sleep_until(&self, at: Instant) -> Sleep {
+        let inner = self.inner.clone();
+        Box::pin(async move {
+            loop {
+                let changed = inner.changed.notified();
+                if *inner.now.lock().unwrap() >= at {
+                    return;
+                }
+                changed.await;
+            }
+        })
+    }
+
+    fn unix_time(&self) -> u64 {
+        (self.now() - self.inner.created).as_secs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use crate::event_queue::EventQueue;
+
+    use super::*;
+
+    #[test]
+    fn advance_fires_events_in_order() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let mut queue = EventQueue::new(Arc::new(clock.clone()));
+        queue.add(3, "third", start + Duration::from_secs(3));
+        queue.add(1, "first", start + Duration::from_secs(1));
+        queue.add(2, "second", start + Duration::from_secs(2));
+        assert!(queue.pop_completed().is_none());
+
+        clock.advance(Duration::from_secs(2));
+        let fired: Vec<_> = iter::from_fn(|| queue.pop_completed())
+            .map(|(_, _, name)| name)
+            .collect();
+        assert_eq!(fired, ["first", "second"]);
+
+        clock.advance_to(start + Duration::from_secs(10));
+        let (at, key, _) = queue.pop_completed().unwrap();
+        assert_eq!((at, key), (start + Duration::from_secs(3), 3));
+        assert!(queue.pop_completed().is_none());
+    }
+
+    #[test]
+    fn unix_time_tracks_advance() {
+        let clock = ManualClock::new();
+        assert_eq!(clock.unix_time(), 0);
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(clock.unix_time(), 1);
+        clock.advance_to(clock.now() + Duration::from_secs(60));
+        assert_eq!(clock.unix_time(), 61);
+        // Never moves backwards
+        clock.advance_to(clock.now() - Duration::from_secs(30));
+        assert_eq!(clock.unix_time(), 61);
+    }
+
+    #[tokio::test]
+    async fn sleep_resolves_once_advanced() {
+        let clock = ManualClock::new();
+        let sleep = tokio::spawn(clock.sleep_until(clock.now() + Duration::from_secs(5)));
+        tokio::task::yield_now().await;
+        clock.advance(Duration::from_secs(4));
+        tokio::task::yield_now().await;
+        assert!(!sleep.is_finished());
+
+        clock.advance(Duration::from_secs(1));
+        tokio::time::timeout(Duration::from_secs(5), sleep)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -1,22 +1,27 @@
 use std::cmp::Ordering;
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, HashSet};
+use std::hash::Hash;
+use std::sync::Arc;
 
 use tokio::time::Instant;
 
+use crate::clock::Clock;
+
 #[derive(Debug)]
-pub struct EventQueueItem<T> {
+pub struct EventQueueItem<K, V> {
     time: Instant,
-    item: T,
+    key: K,
+    value: V,
 }
-impl<T> PartialEq for EventQueueItem<T> {
+impl<K, V> PartialEq for EventQueueItem<K, V> {
     fn eq(&self, other: &Self) -> bool {
         self.time == other.time
     }
 }
 
-impl<T> Eq for EventQueueItem<T> {}
+impl<K, V> Eq for EventQueueItem<K, V> {}
 
-impl<T> PartialOrd for EventQueueItem<T> {
+impl<K, V> PartialOrd for EventQueueItem<K, V> {
     /// Weird ordering, where first value is the one with earlier timestamp.
     /// This works because BinaryHeap is a max-heap.
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
@@ -24,7 +29,7 @@ impl<T> PartialOrd for EventQueueItem<T> {
     }
 }
 
-impl<T> Ord for EventQueueItem<T> {
+impl<K, V> Ord for EventQueueItem<K, V> {
     /// Weird ordering, where first value is the one with earlier timestamp.
     /// This works because BinaryHeap is a max-heap.
     fn cmp(&self, other: &Self) -> Ordering {
@@ -32,33 +37,68 @@ impl<T> Ord for EventQueueItem<T> {
     }
 }
 
-pub struct EventQueue<T> {
-    queue: BinaryHeap<EventQueueItem<T>>,
+/// Timed events with lazy cancellation. Keys must be unique among pending events.
+pub struct EventQueue<K, V> {
+    queue: BinaryHeap<EventQueueItem<K, V>>,
+    /// Keys of events that haven't been cancelled.
+    /// Cancelled events are dropped when they reach the front of the queue.
+    live: HashSet<K>,
+    clock: Arc<dyn Clock>,
 }
 
-impl<T> EventQueue<T> {
-    pub fn new() -> Self {
+impl<K: Clone + Eq + Hash, V> EventQueue<K, V> {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
         Self {
             queue: BinaryHeap::new(),
+            live: HashSet::new(),
+            clock,
         }
     }
 
-    pub fn add(&mut self, item: T, time: Instant) {
-        self.queue.push(EventQueueItem { time, item });
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    pub fn add(&mut self, key: K, value: V, time: Instant) {
+        self.live.insert(key.clone());
+        self.queue.push(EventQueueItem { time, key, value });
+    }
+
+    /// Returns false if there was no such pending event
+    pub fn cancel(&mut self, key: &K) -> bool {
+        self.live.remove(key)
+    }
+
+    /// Cancel all pending events with matching keys
+    pub fn cancel_where(&mut self, f: impl Fn(&K) -> bool) {
+        self.live.retain(|key| !f(key));
     }
 
     pub fn next_timeout(&mut self) -> Option<Instant> {
+        self.drop_cancelled();
         Some(self.queue.peek()?.time)
     }
 
     /// Pop a single, completed event, if any available.
-    pub fn pop_completed(&mut self) -> Option<T> {
-        let now = Instant::now();
+    pub fn pop_completed(&mut self) -> Option<(Instant, K, V)> {
+        self.drop_cancelled();
+        let now = self.clock.now();
         if let Some(item) = self.queue.peek() {
             if item.time <= now {
-                return self.queue.pop().map(|item| item.item);
+                let item = self.queue.pop().unwrap();
+                self.live.remove(&item.key);
+                return Some((item.time, item.key, item.value));
             }
         }
         None
     }
+
+    fn drop_cancelled(&mut self) {
+        while let Some(item) = self.queue.peek() {
+            if self.live.contains(&item.key) {
+                break;
+            }
+            self.queue.pop();
+        }
+    }
 }
@@ -1,12 +1,63 @@
 use std::collections::HashMap;
+use std::time::Duration;
 
+use wgfw_protocol::TeamInfo;
+
+use crate::bots::{Bot, BotMode};
 use crate::game_state::Game;
+use crate::latency::QualityThresholds;
+use crate::rating::RatingSystem;
 
 type Constructor = Box<dyn Fn() -> Box<dyn Game> + Send + Sync>;
+type BotConstructor = Box<dyn Fn() -> Box<dyn Bot> + Send + Sync>;
+
+/// Game mode constraints enforced by the framework
+#[derive(Debug, Clone)]
+pub struct GameModeInfo {
+    /// Players required to start the game
+    pub min_players: usize,
+    /// Maximum lobby size, if any
+    pub max_players: Option<usize>,
+    /// Require all players to confirm they're ready before starting
+    pub ready_check: bool,
+    /// Initial fixed tick rate in Hz, for real-time games. `Some(0)` doesn't
+    /// tick, and rates above [`MAX_TICK_RATE`](crate::game_state::MAX_TICK_RATE)
+    /// are lowered to it.
+    pub tick_rate: Option<u32>,
+    /// Publish a hash of the game seed at start and reveal the seed at the end
+    pub commit_reveal: bool,
+    /// Call `Game::on_connection_quality` when a player crosses these
+    pub quality_thresholds: Option<QualityThresholds>,
+    /// Teams that members are balanced into on join, see [`crate::teams`]
+    pub teams: Vec<TeamInfo>,
+    /// Rate players by the game results, see [`crate::rating`]
+    pub rating: Option<RatingSystem>,
+}
+impl Default for GameModeInfo {
+    fn default() -> Self {
+        Self {
+            min_players: 1,
+            max_players: None,
+            ready_check: false,
+            tick_rate: None,
+            commit_reveal: false,
+            quality_thresholds: None,
+            teams: Vec::new(),
+            rating: None,
+        }
+    }
+}
+
+pub struct GameMode {
+    pub constructor: Constructor,
+    pub info: GameModeInfo,
+}
 
 #[derive(Default)]
 pub struct GameRegistry {
-    pub games: HashMap<String, Constructor>,
+    pub games: HashMap<String, GameMode>,
+    /// Bots by game mode name
+    pub bots: HashMap<String, BotMode>,
 }
 
 impl GameRegistry {
@@ -15,6 +66,27 @@ impl GameRegistry {
     }
 
     pub fn register(&mut self, name: &str, game: Constructor) {
-        self.games.insert(name.to_owned(), game);
+        self.register_with_info(name, game, GameModeInfo::default());
+    }
+
+    pub fn register_with_info(&mut self, name: &str, game: Constructor, info: GameModeInfo) {
+        self.games.insert(
+            name.to_owned(),
+            GameMode {
+                constructor: game,
+                info,
+            },
+        );
+    }
+
+    /// Let lobby leaders add bots to games of this mode
+    pub fn register_bot(&mut self, name: &str, bot: BotConstructor, think_delay: Duration) {
+        self.bots.insert(
+            name.to_owned(),
+            BotMode {
+                constructor: bot,
+                think_delay,
+            },
+        );
     }
 }
@@ -1,24 +1,42 @@
-use std::collections::hash_map::Entry;
+use std::collections::hash_map::{DefaultHasher, Entry};
 use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::hash::{Hash, Hasher};
 use std::net::SocketAddr;
-use std::{iter, mem};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use std::{fmt, io, iter};
 
-use futures::stream::SplitSink;
-use futures_util::{SinkExt, StreamExt};
-use tokio::sync::mpsc;
+use futures::{Sink, Stream};
+use futures_util::{future, sink, stream, SinkExt, StreamExt};
+use serde::Serialize;
+use serde_json::value::RawValue;
+use tokio::sync::{mpsc, oneshot};
 use tokio::task::JoinHandle;
-use tokio::time;
+use tokio::time::{self, Instant};
 use uuid::Uuid;
 use warp::ws::{Message, WebSocket};
 
 use wgfw_protocol::{
-    ClientMessage, ClientMessageData, ErrorReply, GameId, Identity, PlayerId, ReconnectionSecret,
-    ReplyMessage, ServerSentMessage,
+    ChatLine, ClientMessage, ClientMessageData, ErrorReply, GameId, GameResult, Identity,
+    LobbyPhase, MatchRecord, PlayerId, PlayerInfo, Profile, Rating, ReconnectionSecret,
+    ReplyMessage, SeedCommitment, ServerMessage, ServerSentMessage, TeamInfo, TurnInfo,
 };
 
+use crate::auth::{Authenticator, Claims};
+use crate::bots::{BotPlayer, BotView};
+use crate::chat::{self, LobbyChat};
+use crate::clock::{Clock, SystemClock};
 use crate::event_queue::EventQueue;
 use crate::game_registry::GameRegistry;
-use crate::game_state::{EventId, GameCommon, Lobby};
+use crate::game_state::{EventId, GameCommon, Lobby, Notification, ScheduledEvent};
+use crate::history::MatchStore;
+use crate::latency::PlayerConnection;
+use crate::matchmaking::{Matchmaker, Matchmaking, QueueEntry, MIN_MATCH_SIZE};
+use crate::rating::{self, RatingStore};
+use crate::teams::Teams;
 
 /// Browser session
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -29,9 +47,80 @@ impl ConnectionId {
     }
 }
 
+/// Message to a client
+enum Outgoing {
+    Message(ServerMessage),
+    /// Serialized already, sent as is over WebSockets
+    Text(String),
+}
+impl Outgoing {
+    fn into_text(self) -> String {
+        match self {
+            Self::Message(message) => serde_json::to_string(&message).unwrap(),
+            Self::Text(text) => text,
+        }
+    }
+
+    fn into_message(self) -> ServerMessage {
+        match self {
+            Self::Message(message) => message,
+            Self::Text(text) => serde_json::from_str(&text).expect("Serialized by the server"),
+        }
+    }
+}
+impl From<ServerMessage> for Outgoing {
+    fn from(message: ServerMessage) -> Self {
+        Self::Message(message)
+    }
+}
+
+type SinkError = Box<dyn Error + Send + Sync>;
+
+/// Sink that reports when it has been closed, so the transport can be shut
+/// down even if closing the sink itself doesn't do it
+struct SignalClose<S> {
+    inner: Pin<Box<S>>,
+    closed: Option<oneshot::Sender<()>>,
+}
+impl<S: Sink<Outgoing>> Sink<Outgoing> for SignalClose<S> {
+    type Error = S::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.as_mut().poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Outgoing) -> Result<(), S::Error> {
+        self.inner.as_mut().start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        let result = ready!(self.inner.as_mut().poll_close(cx));
+        if let Some(closed) = self.closed.take() {
+            let _ = closed.send(());
+        }
+        Poll::Ready(result)
+    }
+}
+
+/// Outgoing half of a client connection
+struct ClientSink(Pin<Box<dyn Sink<Outgoing, Error = SinkError> + Send>>);
+impl fmt::Debug for ClientSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ClientSink")
+    }
+}
+
 struct Player {
-    tx: SplitSink<WebSocket, Message>,
+    tx: ClientSink,
     identified: bool,
+    /// Hash of the last game state sent, to skip identical updates
+    last_state: HashMap<GameId, u64>,
+    /// Measured latency of this connection
+    connection: PlayerConnection,
 }
 
 #[derive(Debug)]
@@ -42,8 +131,10 @@ struct Event {
 
 #[derive(Debug)]
 enum EventData {
-    Connected(SplitSink<WebSocket, Message>),
+    Connected(ClientSink),
     Disconnected,
+    /// Measured round-trip time of the connection
+    Latency(Duration),
     Message(ClientMessage),
     InvalidMessage(serde_json::Error),
 }
@@ -53,8 +144,61 @@ impl EventData {
     }
 }
 
-pub fn spawn(registry: GameRegistry) -> (JoinHandle<()>, ServerRemote) {
+/// WebSocket ping settings
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    /// Time between pings
+    pub interval: Duration,
+    /// Close the connection if a ping isn't answered in this time
+    pub timeout: Duration,
+}
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Server-wide configuration
+#[derive(Debug)]
+pub struct Settings {
+    /// Number of lobby chat lines to retain
+    pub chat_history_limit: usize,
+    /// Number of records returned for a match history query
+    pub match_history_limit: usize,
+    /// Time source for timers, tick loops and games
+    pub clock: Arc<dyn Clock>,
+    /// Record inputs of all new lobbies for replay
+    pub record_games: bool,
+    /// Ping settings of WebSocket connections
+    pub heartbeat: Heartbeat,
+    /// Rating ranges of the matchmaking queue
+    pub matchmaking: Matchmaking,
+}
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            chat_history_limit: chat::DEFAULT_HISTORY_LIMIT,
+            match_history_limit: 50,
+            clock: Arc::new(SystemClock),
+            record_games: false,
+            heartbeat: Heartbeat::default(),
+            matchmaking: Matchmaking::default(),
+        }
+    }
+}
+
+pub fn spawn(
+    registry: GameRegistry,
+    authenticator: Option<Box<dyn Authenticator>>,
+    match_store: Option<Box<dyn MatchStore>>,
+    rating_store: Box<dyn RatingStore>,
+    settings: Settings,
+) -> (JoinHandle<()>, ServerRemote) {
     let (event_tx, event_rx) = mpsc::channel(64);
+    let heartbeat = settings.heartbeat;
 
     let jh = tokio::spawn(async {
         GameServer {
@@ -62,14 +206,31 @@ pub fn spawn(registry: GameRegistry) -> (JoinHandle<()>, ServerRemote) {
             clients: HashMap::new(),
             players: HashMap::new(),
             games: HashMap::new(),
-            scheduled: EventQueue::new(),
+            shared_states: HashMap::new(),
+            scheduled: EventQueue::new(settings.clock.clone()),
+            ticks: EventQueue::new(settings.clock.clone()),
+            bots: HashMap::new(),
+            bot_turns: EventQueue::new(settings.clock.clone()),
+            matchmaker: Matchmaker::new(settings.matchmaking),
             registry,
+            authenticator,
+            match_store,
+            rating_store,
+            settings,
+            claims: HashMap::new(),
+            profiles: HashMap::new(),
         }
         .run(event_rx)
         .await;
     });
 
-    (jh, ServerRemote { event_tx })
+    (
+        jh,
+        ServerRemote {
+            event_tx,
+            heartbeat,
+        },
+    )
 }
 
 struct GameServer {
@@ -81,68 +242,713 @@ struct GameServer {
     players: HashMap<PlayerId, Player>,
     /// GameId -> Game Lobby mapping
     games: HashMap<GameId, Lobby>,
+    /// Shared part of the last state broadcast of each lobby, reused for
+    /// private-only updates
+    shared_states: HashMap<GameId, Arc<SharedState>>,
     /// Sceduled events
-    scheduled: EventQueue<(GameId, EventId)>,
+    scheduled: EventQueue<(GameId, EventId), ScheduledEvent>,
+    /// Wakeups of lobby tick loops
+    ticks: EventQueue<EventId, GameId>,
+    /// Bots in lobbies
+    bots: HashMap<PlayerId, BotPlayer>,
+    /// Bots waiting for their think delay
+    bot_turns: EventQueue<EventId, PlayerId>,
+    /// Players waiting to be matched
+    matchmaker: Matchmaker,
     /// Game type registry
     registry: GameRegistry,
+    /// External authentication, if enabled
+    authenticator: Option<Box<dyn Authenticator>>,
+    /// Persistent game results, if enabled
+    match_store: Option<Box<dyn MatchStore>>,
+    /// Ratings of rated game modes
+    rating_store: Box<dyn RatingStore>,
+    settings: Settings,
+    /// Verified claims of externally authenticated players
+    claims: HashMap<PlayerId, Claims>,
+    /// Player profiles, kept even when not in any lobby
+    profiles: HashMap<PlayerId, Profile>,
 }
 impl GameServer {
+    /// Send the state to a single player, with the public part of the last
+    /// broadcast. Only the private state is recomputed.
     async fn send_state_to_player(&mut self, game_id: GameId, player_id: PlayerId) {
-        let game = self.games.get(&game_id).unwrap();
+        let Some(game) = self.games.get(&game_id) else {
+            return;
+        };
+        let shared = self
+            .shared_states
+            .entry(game_id)
+            .or_insert_with(|| Arc::new(SharedState::new(game)))
+            .clone();
+        self.send_state(game_id, player_id, &shared).await;
+    }
+
+    async fn broadcast_game_state(&mut self, game_id: GameId) {
+        let Some(game) = self.games.get(&game_id) else {
+            return;
+        };
+        let shared = Arc::new(SharedState::new(game));
+        self.shared_states.insert(game_id, shared.clone());
+        let players: Vec<PlayerId> = game.common.players.iter().copied().collect();
+
+        for player_id in players {
+            self.send_state(game_id, player_id, &shared).await;
+        }
+    }
+
+    async fn send_state(&mut self, game_id: GameId, player_id: PlayerId, shared: &SharedState) {
+        let Some(game) = self.games.get(&game_id) else {
+            return;
+        };
         if !game.common.players.contains(&player_id) {
             return;
         }
 
-        let public_state = game.public_state();
-        let private_state = game.state_for_player(player_id);
+        let own = OwnState {
+            leader: game.common.leader,
+            phase: game.common.phase,
+            mode: &game.mode,
+            turn: game.turn_info(),
+            tick: game.common.ticker.as_ref().map(|ticker| ticker.tick),
+            seed: game.common.seed_commitment.as_ref(),
+            private_state: serde_json::value::to_raw_value(&game.state_for_player(player_id))
+                .unwrap(),
+            team_state: serde_json::value::to_raw_value(&game.state_for_team_of(player_id))
+                .unwrap(),
+        };
+        let mut hasher = DefaultHasher::new();
+        shared.hash.hash(&mut hasher);
+        serde_json::to_writer(HashWriter(&mut hasher), &own).unwrap();
+        let hash = hasher.finish();
+
+        if let Some(bot) = self.bots.get_mut(&player_id) {
+            if bot.last_state.replace(hash) == Some(hash) {
+                return;
+            }
+            let public_state = serde_json::from_str(shared.public_state.get()).unwrap();
+            let view = BotView::new(
+                game_id,
+                game,
+                player_id,
+                shared.players.clone(),
+                public_state,
+            );
+            if let Some((wakeup, at)) = bot.observe(view, self.settings.clock.now()) {
+                self.bot_turns.add(wakeup, player_id, at);
+            }
+            return;
+        }
+
+        let Some(player) = self.players.get_mut(&player_id) else {
+            return;
+        };
+        if player.last_state.insert(game_id, hash) == Some(hash) {
+            return;
+        }
+
+        let message = StateMessage::ServerSent(StateEvent::GameInfo(GameInfoRef {
+            id: game_id,
+            leader: own.leader,
+            phase: own.phase,
+            players: &shared.players,
+            public_state: &shared.public_state,
+            private_state: &own.private_state,
+            mode: own.mode,
+            turn: &own.turn,
+            tick: own.tick,
+            seed: own.seed,
+            teams: &shared.teams,
+            team_state: &own.team_state,
+        }));
+        let text = serde_json::to_string(&message).unwrap();
+        let _ = player.tx.0.send(Outgoing::Text(text)).await;
+    }
+
+    /// Let a bot act on the latest state it has seen
+    fn run_bot(&mut self, bot_id: PlayerId, publish: &mut PublishGameState) {
+        let Some(bot) = self.bots.get_mut(&bot_id) else {
+            return;
+        };
+        let game_id = bot.game_id;
+        let Some(game) = self.games.get_mut(&game_id) else {
+            bot.wakeup = None;
+            return;
+        };
+
+        bot.take_turn(game, bot_id)
+            .apply(game_id, publish, &mut self.scheduled);
+    }
+
+    /// Add a bot of the current game mode to a lobby
+    async fn add_bot(
+        &mut self,
+        game_id: GameId,
+        player_id: PlayerId,
+        publish: &mut PublishGameState,
+    ) -> ReplyMessage {
+        let Some(game) = self.games.get(&game_id) else {
+            return ReplyMessage::Error(ErrorReply::NoSuchGameLobby);
+        };
+        if game.common.leader != player_id {
+            return ReplyMessage::Error(ErrorReply::NotLeader);
+        }
+        let Some(mode) = self.registry.bots.get(&game.mode) else {
+            return ReplyMessage::Error(ErrorReply::BotsNotAvailable);
+        };
+        if !game.can_join() {
+            return ReplyMessage::Error(ErrorReply::CannotJoin);
+        }
+
+        let bot_id = PlayerId::new();
+        let bot = (mode.constructor)();
+        let profile = bot.profile();
+        self.bots
+            .insert(bot_id, BotPlayer::new(game_id, bot, mode.think_delay));
+
+        let game = self.games.get_mut(&game_id).unwrap();
+        game.add_bot(bot_id, profile)
+            .apply(game_id, publish, &mut self.scheduled);
+        ReplyMessage::BotAdded(bot_id)
+    }
+
+    /// Give the bots of a lobby the bot of the new game mode, or remove them
+    /// if it has none
+    fn switch_bots(&mut self, game_id: GameId, publish: &mut PublishGameState) {
+        let Some(game) = self.games.get(&game_id) else {
+            return;
+        };
+        let mut bot_ids: Vec<PlayerId> = game.common.bots.iter().copied().collect();
+        bot_ids.sort();
+
+        match self.registry.bots.get(&game.mode) {
+            Some(mode) => {
+                for bot_id in bot_ids {
+                    let bot = BotPlayer::new(game_id, (mode.constructor)(), mode.think_delay);
+                    if let Some(wakeup) = self.bots.insert(bot_id, bot).and_then(|old| old.wakeup) {
+                        self.bot_turns.cancel(&wakeup);
+                    }
+                }
+            }
+            None => {
+                for bot_id in bot_ids {
+                    self.remove_bot(bot_id, publish);
+                }
+            }
+        }
+    }
+
+    /// Forget a bot that is no longer in its lobby. Returns its lobby.
+    fn drop_bot(&mut self, bot_id: PlayerId) -> Option<GameId> {
+        let bot = self.bots.remove(&bot_id)?;
+        if let Some(wakeup) = bot.wakeup {
+            self.bot_turns.cancel(&wakeup);
+        }
+        Some(bot.game_id)
+    }
+
+    /// Take a bot out of its lobby
+    fn remove_bot(&mut self, bot_id: PlayerId, publish: &mut PublishGameState) {
+        let Some(game_id) = self.drop_bot(bot_id) else {
+            return;
+        };
+        let Some(game) = self.games.get_mut(&game_id) else {
+            return;
+        };
+        if let Ok(updates) = game.leave(bot_id) {
+            updates.apply(game_id, publish, &mut self.scheduled);
+        }
+    }
+
+    /// Store a game result and announce it to the lobby members
+    async fn record_result(&mut self, game_id: GameId, result: GameResult) {
+        let Some(game) = self.games.get(&game_id) else {
+            return;
+        };
 
         let mut players: Vec<_> = game.common.players.iter().copied().collect();
         players.sort();
 
-        let message = ServerSentMessage::GameInfo {
+        if let Some(store) = &self.match_store {
+            let record = MatchRecord {
+                game_id,
+                mode: game.mode.clone(),
+                finished_at: self.settings.clock.unix_time(),
+                players: players.clone(),
+                result: result.clone(),
+            };
+            if let Err(error) = store.save(&record) {
+                log::error!("Failed to store result of game {:?}: {}", game_id, error);
+            }
+        }
+
+        if let Some(system) = game.mode_info.rating {
+            let updated = rating::update_ratings(system, &game.common.ratings, &result);
+            for (player_id, rating) in &updated {
+                if let Err(error) = self.rating_store.save(*player_id, &game.mode, rating) {
+                    log::error!("Failed to store rating of {:?}: {}", player_id, error);
+                }
+            }
+            if !updated.is_empty() {
+                let game = self.games.get_mut(&game_id).unwrap();
+                game.common.ratings.extend(updated);
+                self.broadcast_game_state(game_id).await;
+            }
+        }
+
+        let message = ServerSentMessage::GameResult {
             id: game_id,
-            leader: game.common.leader,
-            players,
-            public_state,
-            private_state,
+            result,
         }
         .finalize();
+        for player_id in players {
+            self.send_to_player(player_id, &message).await;
+        }
+    }
 
-        let response = serde_json::to_string(&message).unwrap();
-        if let Some(player) = self.players.get_mut(&player_id) {
-            let _ = player.tx.send(Message::text(response)).await;
+    /// Add a line to the lobby chat, sent to all members with the other updates
+    fn post_chat(&mut self, game_id: GameId, line: ChatLine, publish: &mut PublishGameState) {
+        if let Some(game) = self.games.get_mut(&game_id) {
+            game.post_chat(line)
+                .apply(game_id, publish, &mut self.scheduled);
         }
     }
 
-    async fn broadcast_game_state(&mut self, game_id: GameId) {
-        let game = self.games.get(&game_id).unwrap();
+    /// Send chat lines to all members of a lobby
+    async fn send_chat(&mut self, game_id: GameId, line: ChatLine) {
+        let Some(game) = self.games.get(&game_id) else {
+            return;
+        };
+        let message = ServerSentMessage::Chat { id: game_id, line }.finalize();
         let players: Vec<PlayerId> = game.common.players.iter().copied().collect();
+        for player_id in players {
+            self.send_to_player(player_id, &message).await;
+        }
+    }
+
+    /// Tell the new leader of a lobby about the promotion, if the leader changed
+    async fn announce_leader_change(&mut self, game_id: GameId, old_leader: PlayerId) {
+        let Some(game) = self.games.get(&game_id) else {
+            return;
+        };
+        let leader = game.common.leader;
+        if leader != old_leader && game.common.players.contains(&leader) {
+            let message = ServerSentMessage::Promoted { id: game_id }.finalize();
+            self.send_to_player(leader, &message).await;
+        }
+    }
+
+    /// Delete a lobby along with its pending events, and tell the members.
+    /// The game state is dropped as is: no `Game::on_leave` calls, a running
+    /// game isn't finished and no result or rating change is recorded.
+    async fn close_lobby(&mut self, game_id: GameId) {
+        let Some(game) = self.games.remove(&game_id) else {
+            return;
+        };
+        self.shared_states.remove(&game_id);
+        self.scheduled.cancel_where(|(id, _)| *id == game_id);
+        if let Some(ticker) = &game.common.ticker {
+            self.ticks.cancel(&ticker.wakeup);
+        }
+
+        for bot_id in &game.common.bots {
+            if let Some(wakeup) = self.bots.remove(bot_id).and_then(|bot| bot.wakeup) {
+                self.bot_turns.cancel(&wakeup);
+            }
+        }
 
+        let mut players: Vec<_> = game.common.players.iter().copied().collect();
+        players.sort();
+
+        let message = ServerSentMessage::LobbyClosed { id: game_id }.finalize();
         for player_id in players {
-            self.send_state_to_player(game_id, player_id).await;
+            self.forget_state(player_id, game_id);
+            self.send_to_player(player_id, &message).await;
         }
     }
 
+    /// Close a lobby whose last human has gone: bots never lead, and nobody
+    /// is left to watch them play
+    async fn close_if_only_bots(&mut self, game_id: GameId) {
+        if self.games.get(&game_id).is_some_and(Lobby::only_bots_left) {
+            self.close_lobby(game_id).await;
+        }
+    }
+
+    /// Drop the hash of the last state sent to a player who is no longer in the lobby
+    fn forget_state(&mut self, player_id: PlayerId, game_id: GameId) {
+        if let Some(player) = self.players.get_mut(&player_id) {
+            player.last_state.remove(&game_id);
+        }
+    }
+
+    async fn send_to_player(&mut self, player_id: PlayerId, message: &ServerMessage) {
+        if let Some(player) = self.players.get_mut(&player_id) {
+            let _ = player.tx.0.send(message.clone().into()).await;
+        }
+    }
+
+    /// Mute or unmute a player in the lobby chat
+    fn set_chat_muted(
+        &mut self,
+        player_id: PlayerId,
+        game_id: GameId,
+        target: PlayerId,
+        muted: bool,
+        publish: &mut PublishGameState,
+    ) -> ReplyMessage {
+        let Some(game) = self.games.get_mut(&game_id) else {
+            return ReplyMessage::Error(ErrorReply::NoSuchGameLobby);
+        };
+
+        if game.common.leader != player_id {
+            return ReplyMessage::Error(ErrorReply::NotLeader);
+        }
+        match game.set_chat_muted(target, muted) {
+            Ok(updates) => {
+                updates.apply(game_id, publish, &mut self.scheduled);
+                ReplyMessage::Ok
+            }
+            Err(error) => ReplyMessage::Error(error),
+        }
+    }
+
+    /// Move a player to a team. Only the leader can move others.
+    fn set_team(
+        &mut self,
+        game_id: GameId,
+        player_id: PlayerId,
+        target: PlayerId,
+        team: Option<String>,
+        publish: &mut PublishGameState,
+    ) -> ReplyMessage {
+        let Some(game) = self.games.get_mut(&game_id) else {
+            return ReplyMessage::Error(ErrorReply::NoSuchGameLobby);
+        };
+
+        if !game.common.players.contains(&player_id) {
+            return ReplyMessage::Error(ErrorReply::NotInThatGame);
+        }
+        if target != player_id && game.common.leader != player_id {
+            return ReplyMessage::Error(ErrorReply::NotLeader);
+        }
+        if !game.common.players.contains(&target) {
+            return ReplyMessage::Error(ErrorReply::NoSuchPlayer);
+        }
+
+        match game.set_team(target, team) {
+            Ok(updates) => {
+                updates
+                    .always_publish()
+                    .apply(game_id, publish, &mut self.scheduled);
+                ReplyMessage::Ok
+            }
+            Err(error) => ReplyMessage::Error(error),
+        }
+    }
+
+    /// Move a connection over to an existing player identity
+    async fn assume_identity(
+        &mut self,
+        client: ConnectionId,
+        player_id: PlayerId,
+        publish: &mut PublishGameState,
+    ) {
+        self.replace_connection(player_id).await;
+        let old_player_id = self.clients.insert(client, player_id).unwrap();
+        let mut old_entry = self.players.remove(&old_player_id).unwrap();
+        old_entry.identified = true;
+        self.players.insert(player_id, old_entry);
+
+        // Notify running games about reconnection
+        let affected_games: HashSet<GameId> = self
+            .games
+            .iter()
+            .filter_map(|(game_id, game)| {
+                if game.common.players.contains(&player_id) {
+                    Some(*game_id)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        let connection = self.players[&player_id].connection.clone();
+        for game_id in affected_games {
+            let game = self.games.get_mut(&game_id).unwrap();
+            game.reconnect(player_id, connection.clone())
+                .always_publish()
+                .apply(game_id, publish, &mut self.scheduled);
+        }
+    }
+
+    /// Close the connection of a player identifying again from another one.
+    /// The player stays in its lobbies, now on the new connection.
+    async fn replace_connection(&mut self, player_id: PlayerId) {
+        let Some(mut replaced) = self.players.remove(&player_id) else {
+            return;
+        };
+        self.clients.retain(|_, id| *id != player_id);
+
+        let message = ServerSentMessage::Error {
+            message: "Signed in from another connection".to_owned(),
+        }
+        .finalize();
+        let _ = replaced.tx.0.send(message.into()).await;
+        let _ = replaced.tx.0.close().await;
+    }
+
+    /// Start, stop or change the tick loop of a lobby
+    fn set_tick_rate(&mut self, game_id: GameId, hz: u32) {
+        let Some(game) = self.games.get_mut(&game_id) else {
+            return;
+        };
+
+        if let Some(old) = &game.common.ticker {
+            self.ticks.cancel(&old.wakeup);
+        }
+
+        game.set_tick_rate(hz);
+        if let Some(ticker) = &game.common.ticker {
+            self.ticks.add(ticker.wakeup, game_id, ticker.next_at);
+        }
+    }
+
+    /// Run due ticks of a lobby and schedule the next wakeup
+    fn run_ticks(&mut self, game_id: GameId, publish: &mut PublishGameState) {
+        let Some(game) = self.games.get_mut(&game_id) else {
+            return;
+        };
+
+        game.run_ticks(self.settings.clock.now())
+            .apply(game_id, publish, &mut self.scheduled);
+        publish.mark_ticked(game_id);
+
+        if let Some(ticker) = game.common.ticker.as_mut() {
+            ticker.wakeup = EventId::new();
+            self.ticks.add(ticker.wakeup, game_id, ticker.next_at);
+        }
+    }
+
+    /// Create a lobby with `leader` joined. Returns `None` if there's no such mode.
+    async fn create_lobby(
+        &mut self,
+        mode_name: String,
+        leader: PlayerId,
+        publish: &mut PublishGameState,
+    ) -> Option<GameId> {
+        let mode = self.registry.games.get(&mode_name)?;
+        let game_id = GameId::new();
+        let tick_rate = mode.info.tick_rate;
+        self.games.insert(
+            game_id,
+            Lobby {
+                common: GameCommon {
+                    chat: LobbyChat::new(self.settings.chat_history_limit),
+                    clock: self.settings.clock.clone(),
+                    teams: Teams::new(mode.info.teams.clone()),
+                    ..GameCommon::new(leader)
+                },
+                state: (mode.constructor)(),
+                mode: mode_name,
+                mode_info: mode.info.clone(),
+                recording: None,
+            },
+        );
+        if self.settings.record_games {
+            self.games.get_mut(&game_id).unwrap().start_recording();
+        }
+        if let Some(hz) = tick_rate {
+            self.set_tick_rate(game_id, hz);
+        }
+        self.join_lobby(game_id, leader, publish).await;
+        Some(game_id)
+    }
+
+    /// Add a player to a lobby and announce it
+    async fn join_lobby(
+        &mut self,
+        game_id: GameId,
+        player_id: PlayerId,
+        publish: &mut PublishGameState,
+    ) {
+        self.add_to_lobby(game_id, player_id);
+        self.games
+            .get_mut(&game_id)
+            .unwrap()
+            .on_join(player_id)
+            .always_publish()
+            .apply(game_id, publish, &mut self.scheduled);
+        self.post_chat(game_id, ChatLine::Joined(player_id), publish);
+        let message = ServerSentMessage::Joined { id: game_id }.finalize();
+        self.send_to_player(player_id, &message).await;
+    }
+
+    /// Create lobbies for matched players and update the queue positions
+    async fn run_matchmaking(&mut self, publish: &mut PublishGameState) {
+        let registry = &self.registry;
+        let matches = self
+            .matchmaker
+            .find_matches(self.settings.clock.now(), |mode| {
+                registry
+                    .games
+                    .get(mode)
+                    .map_or((MIN_MATCH_SIZE, None), |mode| {
+                        (mode.info.min_players, mode.info.max_players)
+                    })
+            });
+
+        for group in matches {
+            let mut entries = group.into_iter();
+            let leader = entries.next().unwrap();
+            let Some(game_id) = self
+                .create_lobby(leader.mode.clone(), leader.player, publish)
+                .await
+            else {
+                // The mode is gone from the registry, so requeueing would
+                // fail again on the next round
+                let message = ServerSentMessage::Error {
+                    message: format!("Game mode {} is no longer available", leader.mode),
+                }
+                .finalize();
+                for player_id in iter::once(leader.player).chain(entries.map(|entry| entry.player))
+                {
+                    self.send_to_player(player_id, &message).await;
+                }
+                continue;
+            };
+            let mut joined = vec![leader.player];
+
+            for entry in entries {
+                if self.games[&game_id].can_join() {
+                    self.join_lobby(game_id, entry.player, publish).await;
+                    joined.push(entry.player);
+                } else {
+                    self.matchmaker.join(entry);
+                }
+            }
+
+            let message = ServerSentMessage::MatchFound {
+                id: game_id,
+                mode: leader.mode,
+            }
+            .finalize();
+            for player_id in joined {
+                self.send_to_player(player_id, &message).await;
+            }
+        }
+
+        self.send_queue_positions().await;
+    }
+
+    /// Tell queued players about changes of their position or queue length
+    async fn send_queue_positions(&mut self) {
+        for (player_id, mode, position, queued) in self.matchmaker.position_changes() {
+            let message = ServerSentMessage::QueuePosition {
+                mode,
+                position,
+                queued,
+            }
+            .finalize();
+            self.send_to_player(player_id, &message).await;
+        }
+    }
+
+    /// Load the ratings of human members for the current game mode
+    fn reload_ratings(&mut self, game_id: GameId, publish: &mut PublishGameState) {
+        let Some(game) = self.games.get_mut(&game_id) else {
+            return;
+        };
+        let mut ratings = HashMap::new();
+        if game.mode_info.rating.is_some() {
+            for player_id in &game.common.players {
+                if game.common.bots.contains(player_id) {
+                    continue;
+                }
+                if let Some(rating) = load_rating(&*self.rating_store, *player_id, &game.mode) {
+                    ratings.insert(*player_id, rating);
+                }
+            }
+        }
+        game.set_ratings(ratings)
+            .apply(game_id, publish, &mut self.scheduled);
+    }
+
+    /// Add a player to the lobby, along with their claims and profile
+    fn add_to_lobby(&mut self, game_id: GameId, player_id: PlayerId) {
+        let mut connection = PlayerConnection::new();
+        if let Some(player) = self.players.get_mut(&player_id) {
+            player.last_state.remove(&game_id);
+            connection = player.connection.clone();
+        }
+
+        let game = self.games.get_mut(&game_id).unwrap();
+        if game.mode_info.rating.is_some() && !self.bots.contains_key(&player_id) {
+            if let Some(rating) = load_rating(&*self.rating_store, player_id, &game.mode) {
+                game.common.ratings.insert(player_id, rating);
+            }
+        }
+
+        let common = &mut game.common;
+        common.players.insert(player_id);
+        if let Some(claims) = self.claims.get(&player_id) {
+            common.claims.insert(player_id, claims.clone());
+        }
+        if let Some(profile) = self.profiles.get(&player_id) {
+            common.profiles.insert(player_id, profile.clone());
+        }
+        common.connections.insert(player_id, connection);
+    }
+
     async fn run(mut self, mut event_rx: mpsc::Receiver<Event>) {
         loop {
             // Process pending events
-            while let Some((game_id, event_id)) = self.scheduled.pop_completed() {
+            let mut publish = PublishGameState::default();
+            while let Some((at, (game_id, event_id), event)) = self.scheduled.pop_completed() {
                 if let Some(game) = self.games.get_mut(&game_id) {
-                    if game
-                        .on_event(event_id)
-                        .apply_schedule(game_id, &mut self.scheduled)
-                    {
-                        self.broadcast_game_state(game_id).await;
+                    if let Some((next_at, next)) = event.repeat(at) {
+                        self.scheduled.add((game_id, event_id), next, next_at);
                     }
+
+                    game.on_event(event_id, event.payload).apply(
+                        game_id,
+                        &mut publish,
+                        &mut self.scheduled,
+                    );
                 }
             }
 
-            let event = if let Some(at) = self.scheduled.next_timeout() {
-                if let Ok(event) = time::timeout_at(at, event_rx.recv()).await {
-                    event
-                } else {
-                    // Timeout
-                    continue;
+            // Run tick loops
+            while let Some((_, _, game_id)) = self.ticks.pop_completed() {
+                self.run_ticks(game_id, &mut publish);
+            }
+
+            // Let bots act
+            while let Some((_, _, bot_id)) = self.bot_turns.pop_completed() {
+                self.run_bot(bot_id, &mut publish);
+            }
+
+            // Rematch the queue with widened rating ranges
+            let now = self.settings.clock.now();
+            if self.matchmaker.next_round().is_some_and(|at| at <= now) {
+                self.run_matchmaking(&mut publish).await;
+            }
+            publish.apply(&mut self).await;
+
+            let next_timeout = [
+                self.scheduled.next_timeout(),
+                self.ticks.next_timeout(),
+                self.bot_turns.next_timeout(),
+                self.matchmaker.next_round(),
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+
+            let event = if let Some(at) = next_timeout {
+                tokio::select! {
+                    event = event_rx.recv() => event,
+                    () = self.settings.clock.sleep_until(at) => continue,
                 }
             } else {
                 event_rx.recv().await
@@ -170,13 +976,21 @@ impl GameServer {
                     Player {
                         tx,
                         identified: false,
+                        last_state: HashMap::new(),
+                        connection: PlayerConnection::new(),
                     },
                 );
                 debug_assert!(old.is_none(), "The client id should never conflict");
             }
             EventData::Disconnected => {
-                let player_id = self.clients.remove(&event.client).unwrap();
-                let _ = self.players.remove(&player_id).unwrap();
+                // Replaced connections are already gone
+                let Some(player_id) = self.clients.remove(&event.client) else {
+                    return;
+                };
+                self.players.remove(&player_id);
+                if self.matchmaker.leave(player_id) {
+                    self.send_queue_positions().await;
+                }
                 let affected_games: HashSet<GameId> = self
                     .games
                     .iter()
@@ -189,32 +1003,52 @@ impl GameServer {
                     })
                     .collect();
 
+                let mut publish = PublishGameState::default();
                 for game_id in affected_games {
-                    let publish = self
-                        .games
-                        .get_mut(&game_id)
-                        .unwrap()
-                        .on_disconnect(player_id)
-                        .always_publish()
-                        .apply_schedule(game_id, &mut self.scheduled);
-
-                    if publish {
-                        self.broadcast_game_state(game_id).await;
+                    let game = self.games.get_mut(&game_id).unwrap();
+                    game.disconnect(player_id).always_publish().apply(
+                        game_id,
+                        &mut publish,
+                        &mut self.scheduled,
+                    );
+                }
+                publish.apply(self).await;
+            }
+            EventData::Latency(rtt) => {
+                let Some(player_id) = self.clients.get(&event.client).copied() else {
+                    return;
+                };
+                let Some(player) = self.players.get_mut(&player_id) else {
+                    return;
+                };
+                player.connection.sample(rtt);
+                let connection = player.connection.clone();
+
+                let mut publish = PublishGameState::default();
+                for (game_id, game) in self.games.iter_mut() {
+                    if !game.common.players.contains(&player_id) {
+                        continue;
                     }
+
+                    game.update_connection(player_id, connection.clone()).apply(
+                        *game_id,
+                        &mut publish,
+                        &mut self.scheduled,
+                    );
                 }
+                publish.apply(self).await;
             }
             EventData::InvalidMessage(error) => {
-                let player_id = self.clients.get(&event.client).unwrap();
+                let Some(player_id) = self.clients.get(&event.client) else {
+                    return;
+                };
                 let player = self.players.get_mut(player_id).unwrap();
 
-                let response = serde_json::to_string(
-                    &ServerSentMessage::Error {
-                        message: format!("{}", error),
-                    }
-                    .finalize(),
-                )
-                .unwrap();
-                player.tx.send(Message::text(response)).await.unwrap();
+                let response = ServerSentMessage::Error {
+                    message: format!("{}", error),
+                }
+                .finalize();
+                let _ = player.tx.0.send(response.into()).await;
             }
             EventData::Message(cmsg) => self.process_client_message(event.client, cmsg).await,
         };
@@ -224,12 +1058,16 @@ impl GameServer {
         let mut publish = PublishGameState::default();
 
         let ClientMessage { id: msgid, data } = msg;
-        let mut player_id = *self.clients.get(&client).unwrap();
+        let Some(mut player_id) = self.clients.get(&client).copied() else {
+            return;
+        };
 
         let is_identified = self.players.get(&player_id).unwrap().identified;
         let attempts_to_identify = matches!(
             data,
-            ClientMessageData::NewIdentity | ClientMessageData::Identify(..)
+            ClientMessageData::NewIdentity
+                | ClientMessageData::Identify(..)
+                | ClientMessageData::Authenticate(..)
         );
 
         let response: ReplyMessage = if is_identified && attempts_to_identify {
@@ -250,38 +1088,37 @@ impl GameServer {
                 }
                 ClientMessageData::Identify(identity) => {
                     if identity.verify(&self.secret) {
-                        let old_player_id = mem::replace(&mut player_id, identity.player_id);
-                        self.clients.insert(client, identity.player_id);
-                        let mut old_entry = self.players.remove(&old_player_id).unwrap();
-                        old_entry.identified = true;
-                        self.players.insert(identity.player_id, old_entry);
-
-                        // Notify running games about reconnection
-                        let affected_games: HashSet<GameId> = self
-                            .games
-                            .iter()
-                            .filter_map(|(game_id, game)| {
-                                if game.common.players.contains(&player_id) {
-                                    Some(*game_id)
-                                } else {
-                                    None
-                                }
-                            })
-                            .collect();
-
-                        for game_id in affected_games {
-                            self.games
-                                .get_mut(&game_id)
-                                .unwrap()
-                                .on_reconnect(player_id)
-                                .always_publish()
-                                .apply(game_id, &mut publish, &mut self.scheduled);
-                        }
+                        player_id = identity.player_id;
+                        self.assume_identity(client, player_id, &mut publish).await;
                         ReplyMessage::Identity(identity)
                     } else {
                         ReplyMessage::Error(ErrorReply::InvalidReconnectionSecret)
                     }
                 }
+                ClientMessageData::Authenticate(token) => {
+                    if let Some(authenticator) = &self.authenticator {
+                        match authenticator.authenticate(&token) {
+                            Ok(claims) => {
+                                player_id = authenticator.player_id(&claims);
+                                self.claims.insert(player_id, claims);
+                                self.assume_identity(client, player_id, &mut publish).await;
+                                ReplyMessage::Identity(Identity {
+                                    player_id,
+                                    reconnection_secret: ReconnectionSecret::for_player(
+                                        &self.secret,
+                                        player_id,
+                                    ),
+                                })
+                            }
+                            Err(error) => {
+                                log::debug!("Authentication failed: {:?}", error);
+                                ReplyMessage::Error(ErrorReply::InvalidAuthenticationToken)
+                            }
+                        }
+                    } else {
+                        ReplyMessage::Error(ErrorReply::AuthenticationNotSupported)
+                    }
+                }
                 ClientMessageData::GameModes => {
                     ReplyMessage::GameModes(self.registry.games.keys().cloned().collect())
                 }
@@ -298,72 +1135,312 @@ impl GameServer {
                         })
                         .collect();
 
-                    // Send game state to player
+                    // Send game state to player, even if it hasn't changed
+                    if let Some(player) = self.players.get_mut(&player_id) {
+                        player.last_state.clear();
+                    }
                     for game_id in games.iter() {
                         publish.add(*game_id, player_id);
                     }
 
                     ReplyMessage::JoinedGames(games)
                 }
-                ClientMessageData::CreateGame(game_type) => {
-                    let game_id = GameId::new();
-                    if let Some(constructor) = self.registry.games.get(&game_type) {
-                        let state = constructor();
-                        self.games.insert(
-                            game_id,
-                            Lobby {
-                                common: GameCommon {
-                                    leader: player_id,
-                                    players: iter::once(player_id).collect(),
-                                },
-                                state,
-                            },
-                        );
-                        self.games
-                            .get_mut(&game_id)
-                            .unwrap()
-                            .on_join(player_id)
-                            .always_publish()
-                            .apply(game_id, &mut publish, &mut self.scheduled);
-                        ReplyMessage::GameCreated(game_id)
+                ClientMessageData::MatchHistory => {
+                    if let Some(store) = &self.match_store {
+                        match store.history(player_id, self.settings.match_history_limit) {
+                            Ok(records) => ReplyMessage::MatchHistory(records),
+                            Err(error) => {
+                                log::error!("Failed to read match history: {}", error);
+                                ReplyMessage::Error(ErrorReply::MatchHistoryNotAvailable)
+                            }
+                        }
                     } else {
-                        ReplyMessage::Error(ErrorReply::InvalidGameFormat)
+                        ReplyMessage::Error(ErrorReply::MatchHistoryNotAvailable)
+                    }
+                }
+                ClientMessageData::CreateGame(game_type) => {
+                    match self.create_lobby(game_type, player_id, &mut publish).await {
+                        Some(game_id) => ReplyMessage::GameCreated(game_id),
+                        None => ReplyMessage::Error(ErrorReply::InvalidGameFormat),
                     }
                 }
                 ClientMessageData::JoinGame(game_id) => {
                     let player_id = *self.clients.get(&client).unwrap();
 
-                    if let Some(game) = self.games.get_mut(&game_id) {
-                        game.common.players.insert(player_id);
-                        self.games
-                            .get_mut(&game_id)
-                            .unwrap()
-                            .on_join(player_id)
-                            .always_publish()
-                            .apply(game_id, &mut publish, &mut self.scheduled);
-                        ReplyMessage::JoinedToGame(game_id)
+                    if let Some(game) = self.games.get(&game_id) {
+                        if !game.can_join() {
+                            ReplyMessage::Error(ErrorReply::CannotJoin)
+                        } else {
+                            self.join_lobby(game_id, player_id, &mut publish).await;
+                            ReplyMessage::JoinedToGame(game_id)
+                        }
                     } else {
                         ReplyMessage::Error(ErrorReply::NoSuchGameLobby)
                     }
                 }
+                ClientMessageData::QueueForMatch { mode, params } => {
+                    if !self.registry.games.contains_key(&mode) {
+                        ReplyMessage::Error(ErrorReply::InvalidGameFormat)
+                    } else if self.matchmaker.contains(player_id) {
+                        ReplyMessage::Error(ErrorReply::AlreadyQueued)
+                    } else {
+                        // Rated modes match by the stored rating
+                        let rating = if self.registry.games[&mode].info.rating.is_some() {
+                            load_rating(&*self.rating_store, player_id, &mode)
+                                .map(|rating| rating.rating.max(0.0).round() as u32)
+                        } else {
+                            params.rating
+                        };
+                        self.matchmaker.join(QueueEntry {
+                            player: player_id,
+                            mode,
+                            rating,
+                            since: self.settings.clock.now(),
+                        });
+                        self.run_matchmaking(&mut publish).await;
+                        ReplyMessage::Ok
+                    }
+                }
+                ClientMessageData::LeaveQueue => {
+                    if self.matchmaker.leave(player_id) {
+                        self.send_queue_positions().await;
+                        ReplyMessage::Ok
+                    } else {
+                        ReplyMessage::Error(ErrorReply::NotQueued)
+                    }
+                }
                 ClientMessageData::LeaveGame(game_id) => {
                     let player_id = *self.clients.get(&client).unwrap();
 
                     if let Some(game) = self.games.get_mut(&game_id) {
-                        game.common.players.remove(&player_id);
-                        self.games
-                            .get_mut(&game_id)
-                            .unwrap()
-                            .on_leave(player_id)
-                            .always_publish()
-                            .apply(game_id, &mut publish, &mut self.scheduled);
+                        let leader = game.common.leader;
+                        match game.leave(player_id) {
+                            Ok(updates) => {
+                                updates.apply(game_id, &mut publish, &mut self.scheduled);
+                                self.forget_state(player_id, game_id);
+                                let message = ServerSentMessage::Left { id: game_id }.finalize();
+                                self.send_to_player(player_id, &message).await;
+                                self.announce_leader_change(game_id, leader).await;
+                                self.close_if_only_bots(game_id).await;
+                                ReplyMessage::Ok
+                            }
+                            Err(error) => ReplyMessage::Error(error),
+                        }
+                    } else {
+                        ReplyMessage::Error(ErrorReply::NoSuchGameLobby)
+                    }
+                }
+                ClientMessageData::SetProfile(profile) => match profile.validate() {
+                    Ok(()) => {
+                        for (game_id, game) in self.games.iter_mut() {
+                            if game.common.players.contains(&player_id) {
+                                game.set_profile(player_id, profile.clone()).apply(
+                                    *game_id,
+                                    &mut publish,
+                                    &mut self.scheduled,
+                                );
+                            }
+                        }
+                        self.profiles.insert(player_id, profile);
                         ReplyMessage::Ok
+                    }
+                    Err(error) => ReplyMessage::Error(ErrorReply::InvalidProfile(error)),
+                },
+                ClientMessageData::KickPlayer(game_id, target, reason) => {
+                    if let Some(game) = self.games.get_mut(&game_id) {
+                        if game.common.leader != player_id {
+                            ReplyMessage::Error(ErrorReply::NotLeader)
+                        } else if reason.as_ref().is_some_and(|reason| {
+                            reason.chars().count() > ClientMessageData::MAX_KICK_REASON_LEN
+                        }) {
+                            ReplyMessage::Error(ErrorReply::KickReasonTooLong)
+                        } else if let Ok(updates) = game.kick(target) {
+                            updates.apply(game_id, &mut publish, &mut self.scheduled);
+                            self.drop_bot(target);
+                            self.forget_state(target, game_id);
+                            let message = ServerSentMessage::Kicked {
+                                id: game_id,
+                                reason,
+                            }
+                            .finalize();
+                            self.send_to_player(target, &message).await;
+                            self.announce_leader_change(game_id, player_id).await;
+                            self.close_if_only_bots(game_id).await;
+                            ReplyMessage::Ok
+                        } else {
+                            ReplyMessage::Error(ErrorReply::NoSuchPlayer)
+                        }
+                    } else {
+                        ReplyMessage::Error(ErrorReply::NoSuchGameLobby)
+                    }
+                }
+                ClientMessageData::PromoteLeader(game_id, target) => {
+                    if let Some(game) = self.games.get_mut(&game_id) {
+                        if game.common.leader != player_id {
+                            ReplyMessage::Error(ErrorReply::NotLeader)
+                        } else {
+                            match game.promote(target) {
+                                Ok(updates) => {
+                                    updates.apply(game_id, &mut publish, &mut self.scheduled);
+                                    self.announce_leader_change(game_id, player_id).await;
+                                    ReplyMessage::Ok
+                                }
+                                Err(error) => ReplyMessage::Error(error),
+                            }
+                        }
+                    } else {
+                        ReplyMessage::Error(ErrorReply::NoSuchGameLobby)
+                    }
+                }
+                ClientMessageData::CloseGame(game_id) => {
+                    if let Some(game) = self.games.get(&game_id) {
+                        if game.common.leader != player_id {
+                            ReplyMessage::Error(ErrorReply::NotLeader)
+                        } else {
+                            self.close_lobby(game_id).await;
+                            ReplyMessage::Ok
+                        }
+                    } else {
+                        ReplyMessage::Error(ErrorReply::NoSuchGameLobby)
+                    }
+                }
+                ClientMessageData::AddBot(game_id) => {
+                    self.add_bot(game_id, player_id, &mut publish).await
+                }
+                ClientMessageData::RemoveBot(game_id, target) => {
+                    if let Some(game) = self.games.get(&game_id) {
+                        if game.common.leader != player_id {
+                            ReplyMessage::Error(ErrorReply::NotLeader)
+                        } else if !game.common.bots.contains(&target) {
+                            ReplyMessage::Error(ErrorReply::NotABot)
+                        } else {
+                            self.remove_bot(target, &mut publish);
+                            ReplyMessage::Ok
+                        }
+                    } else {
+                        ReplyMessage::Error(ErrorReply::NoSuchGameLobby)
+                    }
+                }
+                ClientMessageData::JoinTeam(game_id, team) => {
+                    self.set_team(game_id, player_id, player_id, Some(team), &mut publish)
+                }
+                ClientMessageData::LeaveTeam(game_id) => {
+                    self.set_team(game_id, player_id, player_id, None, &mut publish)
+                }
+                ClientMessageData::AssignTeam(game_id, target, team) => {
+                    self.set_team(game_id, player_id, target, team, &mut publish)
+                }
+                ClientMessageData::StartGame(game_id) => {
+                    if let Some(game) = self.games.get_mut(&game_id) {
+                        if game.common.leader != player_id {
+                            ReplyMessage::Error(ErrorReply::NotLeader)
+                        } else {
+                            match game.start() {
+                                Ok(updates) => {
+                                    updates.apply(game_id, &mut publish, &mut self.scheduled);
+                                    ReplyMessage::Ok
+                                }
+                                Err(error) => ReplyMessage::Error(error),
+                            }
+                        }
+                    } else {
+                        ReplyMessage::Error(ErrorReply::NoSuchGameLobby)
+                    }
+                }
+                ClientMessageData::ChangeGameMode(game_id, mode_name) => {
+                    if let Some(game) = self.games.get_mut(&game_id) {
+                        if game.common.leader != player_id {
+                            ReplyMessage::Error(ErrorReply::NotLeader)
+                        } else if let Some(mode) = self.registry.games.get(&mode_name) {
+                            let tick_rate = mode.info.tick_rate;
+                            let wakeup = game.common.ticker.as_ref().map(|t| t.wakeup);
+                            match game.change_mode(
+                                mode_name,
+                                (mode.constructor)(),
+                                mode.info.clone(),
+                            ) {
+                                Ok(updates) => {
+                                    self.scheduled.cancel_where(|(id, _)| *id == game_id);
+                                    if let Some(wakeup) = wakeup {
+                                        self.ticks.cancel(&wakeup);
+                                    }
+                                    self.set_tick_rate(game_id, tick_rate.unwrap_or(0));
+                                    updates.apply(game_id, &mut publish, &mut self.scheduled);
+                                    self.switch_bots(game_id, &mut publish);
+                                    self.reload_ratings(game_id, &mut publish);
+                                    ReplyMessage::Ok
+                                }
+                                Err(error) => ReplyMessage::Error(error),
+                            }
+                        } else {
+                            ReplyMessage::Error(ErrorReply::InvalidGameFormat)
+                        }
+                    } else {
+                        ReplyMessage::Error(ErrorReply::NoSuchGameLobby)
+                    }
+                }
+                ClientMessageData::SetReady(game_id, ready) => {
+                    if let Some(game) = self.games.get_mut(&game_id) {
+                        if !game.common.players.contains(&player_id) {
+                            ReplyMessage::Error(ErrorReply::NotInThatGame)
+                        } else {
+                            match game.set_ready(player_id, ready) {
+                                Ok(updates) => {
+                                    updates.apply(game_id, &mut publish, &mut self.scheduled);
+                                    ReplyMessage::Ok
+                                }
+                                Err(error) => ReplyMessage::Error(error),
+                            }
+                        }
+                    } else {
+                        ReplyMessage::Error(ErrorReply::NoSuchGameLobby)
+                    }
+                }
+                ClientMessageData::SendChat(game_id, text) => {
+                    if let Some(game) = self.games.get_mut(&game_id) {
+                        match game.send_chat(player_id, text) {
+                            Ok(updates) => {
+                                updates.apply(game_id, &mut publish, &mut self.scheduled);
+                                ReplyMessage::Ok
+                            }
+                            Err(error) => ReplyMessage::Error(error),
+                        }
                     } else {
                         ReplyMessage::Error(ErrorReply::NoSuchGameLobby)
                     }
                 }
-                ClientMessageData::KickPlayer(_, _) => todo!("KickPlayer"),
-                ClientMessageData::PromoteLeader(_, _) => todo!("PromoteLeader"),
+                ClientMessageData::ChatHistory(game_id) => {
+                    if let Some(game) = self.games.get(&game_id) {
+                        match game.chat_history(player_id) {
+                            Ok(lines) => ReplyMessage::ChatHistory(lines),
+                            Err(error) => ReplyMessage::Error(error),
+                        }
+                    } else {
+                        ReplyMessage::Error(ErrorReply::NoSuchGameLobby)
+                    }
+                }
+                ClientMessageData::ExportGameLog(game_id) => {
+                    if let Some(game) = self.games.get(&game_id) {
+                        if game.common.leader != player_id {
+                            ReplyMessage::Error(ErrorReply::NotLeader)
+                        } else {
+                            match game.game_log() {
+                                Ok(log) => {
+                                    ReplyMessage::GameLog(serde_json::to_value(log).unwrap())
+                                }
+                                Err(err) => ReplyMessage::Error(err),
+                            }
+                        }
+                    } else {
+                        ReplyMessage::Error(ErrorReply::NoSuchGameLobby)
+                    }
+                }
+                ClientMessageData::MuteChat(game_id, target) => {
+                    self.set_chat_muted(player_id, game_id, target, true, &mut publish)
+                }
+                ClientMessageData::UnmuteChat(game_id, target) => {
+                    self.set_chat_muted(player_id, game_id, target, false, &mut publish)
+                }
                 ClientMessageData::Inner(game_id, inner_data) => {
                     if let Some(game) = self.games.get_mut(&game_id) {
                         if game.common.players.contains(&player_id) {
@@ -384,23 +1461,127 @@ impl GameServer {
         };
 
         let reply = response.finalize(msgid);
-        self.players
+        let _ = self
+            .players
             .get_mut(&player_id)
             .unwrap()
             .tx
-            .send(Message::text(serde_json::to_string(&reply).unwrap()))
-            .await
-            .unwrap();
+            .0
+            .send(reply.into())
+            .await;
 
         publish.apply(self).await;
     }
 }
 
+/// Parts of a game state message that are the same for all players
+struct SharedState {
+    public_state: Box<RawValue>,
+    players: Vec<PlayerInfo>,
+    teams: Vec<TeamInfo>,
+    /// Hash of the parts above, computed once for all recipients
+    hash: u64,
+}
+impl SharedState {
+    fn new(game: &Lobby) -> Self {
+        let public_state = serde_json::value::to_raw_value(&game.public_state()).unwrap();
+        let players = game.common.player_infos();
+        let teams = game.common.teams.teams().to_vec();
+        let mut hasher = DefaultHasher::new();
+        public_state.get().hash(&mut hasher);
+        serde_json::to_writer(HashWriter(&mut hasher), &(&players, &teams)).unwrap();
+        Self {
+            public_state,
+            players,
+            teams,
+            hash: hasher.finish(),
+        }
+    }
+}
+
+/// Parts of a game state message that are computed for each player
+#[derive(Serialize)]
+struct OwnState<'a> {
+    leader: PlayerId,
+    phase: LobbyPhase,
+    mode: &'a str,
+    turn: Option<TurnInfo>,
+    tick: Option<u64>,
+    seed: Option<&'a SeedCommitment>,
+    private_state: Box<RawValue>,
+    team_state: Box<RawValue>,
+}
+
+/// Serializes like a [`ServerSentMessage::GameInfo`] message, reusing the
+/// already serialized states
+#[derive(Serialize)]
+enum StateMessage<'a> {
+    ServerSent(StateEvent<'a>),
+}
+
+#[derive(Serialize)]
+enum StateEvent<'a> {
+    GameInfo(GameInfoRef<'a>),
+}
+
+/// [`wgfw_protocol::GameInfo`] with borrowed, already serialized states
+#[derive(Serialize)]
+struct GameInfoRef<'a> {
+    id: GameId,
+    leader: PlayerId,
+    phase: LobbyPhase,
+    players: &'a [PlayerInfo],
+    public_state: &'a RawValue,
+    private_state: &'a RawValue,
+    mode: &'a str,
+    turn: &'a Option<TurnInfo>,
+    tick: Option<u64>,
+    seed: Option<&'a SeedCommitment>,
+    teams: &'a [TeamInfo],
+    team_state: &'a RawValue,
+}
+
+/// Feeds serialized output into a hasher without building the string
+struct HashWriter<'a>(&'a mut DefaultHasher);
+impl io::Write for HashWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Stored rating, or the initial one for new players. `None` if the store
+/// failed, so that the player is left unrated instead of overwriting the
+/// stored rating.
+fn load_rating(store: &dyn RatingStore, player_id: PlayerId, mode: &str) -> Option<Rating> {
+    match store.load(player_id, mode) {
+        Ok(rating) => Some(rating.unwrap_or_default()),
+        Err(error) => {
+            log::error!("Failed to load rating of {:?}: {}", player_id, error);
+            None
+        }
+    }
+}
+
 /// Keeps track of which game states need sending to which players
 #[derive(Debug, Default)]
 pub(crate) struct PublishGameState {
     /// `None` as value means all players
-    games: HashMap<GameId, Option<HashSet<PlayerId>>>,
+    pub(crate) games: HashMap<GameId, Option<HashSet<PlayerId>>>,
+    /// Results reported by finished games
+    pub(crate) results: Vec<(GameId, GameResult)>,
+    /// Requested tick rate changes
+    pub(crate) tick_rates: Vec<(GameId, u32)>,
+    /// Games that ran a tick, so their broadcasts aren't deferred
+    pub(crate) ticked: HashSet<GameId>,
+    /// One-off game notifications
+    pub(crate) notifications: Vec<(GameId, Notification)>,
+    /// Lobby chat lines
+    pub(crate) chat: Vec<(GameId, ChatLine)>,
 }
 impl PublishGameState {
     pub fn add(&mut self, game_id: GameId, player_id: PlayerId) {
@@ -420,22 +1601,85 @@ impl PublishGameState {
         self.games.insert(game_id, None);
     }
 
+    pub fn add_result(&mut self, game_id: GameId, result: GameResult) {
+        self.results.push((game_id, result));
+    }
+
+    pub fn set_tick_rate(&mut self, game_id: GameId, hz: u32) {
+        self.tick_rates.push((game_id, hz));
+    }
+
+    pub fn mark_ticked(&mut self, game_id: GameId) {
+        self.ticked.insert(game_id);
+    }
+
+    pub fn add_notification(&mut self, game_id: GameId, notification: Notification) {
+        self.notifications.push((game_id, notification));
+    }
+
+    pub fn add_chat(&mut self, game_id: GameId, line: ChatLine) {
+        self.chat.push((game_id, line));
+    }
+
     async fn apply(self, server: &mut GameServer) {
+        for (game_id, hz) in self.tick_rates {
+            server.set_tick_rate(game_id, hz);
+        }
+
+        for (game_id, result) in self.results {
+            server.record_result(game_id, result).await;
+        }
+
+        for (game_id, line) in self.chat {
+            server.send_chat(game_id, line).await;
+        }
+
         for (game_id, players) in self.games {
             if let Some(players) = players {
+                if server
+                    .games
+                    .get(&game_id)
+                    .is_some_and(Lobby::broadcast_pending)
+                {
+                    continue;
+                }
                 for player_id in players {
                     server.send_state_to_player(game_id, player_id).await;
                 }
-            } else {
+            } else if !server
+                .games
+                .get_mut(&game_id)
+                .is_some_and(|game| game.defer_broadcast(self.ticked.contains(&game_id)))
+            {
                 server.broadcast_game_state(game_id).await;
             }
         }
+
+        for (game_id, notification) in self.notifications {
+            let Some(game) = server.games.get_mut(&game_id) else {
+                continue;
+            };
+            let ticked = self.ticked.contains(&game_id);
+            let Some(notification) = game.defer_notification(notification, ticked) else {
+                continue;
+            };
+            let players = notification.to.resolve(&game.common);
+            let message = ServerSentMessage::GameEvent {
+                id: game_id,
+                payload: notification.payload,
+            }
+            .finalize();
+            for player_id in players {
+                server.send_to_player(player_id, &message).await;
+            }
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct ServerRemote {
     event_tx: mpsc::Sender<Event>,
+    heartbeat: Heartbeat,
 }
 impl ServerRemote {
     pub fn make_client_handle(&self, peer_addr: SocketAddr) -> ClientHandle {
@@ -453,48 +1697,135 @@ pub struct ClientHandle {
 }
 
 impl ClientHandle {
+    /// Serve a WebSocket client, pinging it as configured in [`Heartbeat`]
     pub async fn handle_ws_client(self, websocket: WebSocket) {
         let client_id = ConnectionId::new();
+        let (ws_tx, mut ws_rx) = websocket.split();
+        let ws_tx = Arc::new(tokio::sync::Mutex::new(ws_tx));
+
+        let tx = sink::unfold(ws_tx.clone(), |ws_tx, message: Outgoing| async move {
+            ws_tx
+                .lock()
+                .await
+                .send(Message::text(message.into_text()))
+                .await?;
+            Ok::<_, warp::Error>(ws_tx)
+        });
+        let (closed_tx, mut closed_rx) = oneshot::channel();
+        let tx = SignalClose {
+            inner: Box::pin(tx),
+            closed: Some(closed_tx),
+        };
+
+        let (in_tx, in_rx) = mpsc::channel(16);
+        let rx = stream::unfold(in_rx, |mut in_rx| async move {
+            let message = in_rx.recv().await?;
+            Some((message, in_rx))
+        });
+
+        let event_tx = self.server.event_tx.clone();
+        let heartbeat = self.server.heartbeat;
+        let reader = async move {
+            let mut next_ping = Instant::now();
+            let mut ping_sent: Option<Instant> = None;
+            loop {
+                let pong_by = ping_sent.map(|sent| sent + heartbeat.timeout);
+                tokio::select! {
+                    body = ws_rx.next() => {
+                        let message = match body {
+                            Some(Ok(message)) => message,
+                            Some(Err(e)) => {
+                                eprintln!("error reading message on websocket: {}", e);
+                                break;
+                            }
+                            None => break,
+                        };
+
+                        if message.is_pong() {
+                            if let Some(sent) = ping_sent.take() {
+                                let rtt = EventData::Latency(sent.elapsed());
+                                let _ = event_tx.send(rtt.finalize(client_id)).await;
+                            }
+                        } else if let Ok(text) = message.to_str() {
+                            // Skip non-text messages
+                            let message = serde_json::from_str::<ClientMessage>(text);
+                            if in_tx.send(message).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    () = time::sleep_until(next_ping), if ping_sent.is_none() => {
+                        let now = Instant::now();
+                        ping_sent = Some(now);
+                        next_ping = now + heartbeat.interval;
+                        let ping = async { ws_tx.lock().await.send(Message::ping(Vec::new())).await };
+                        let _ = time::timeout(heartbeat.timeout, ping).await;
+                    }
+                    () = time::sleep_until(pong_by.unwrap_or(next_ping)), if pong_by.is_some() => {
+                        log::debug!("Connection {:?} stopped responding", client_id);
+                        break;
+                    }
+                    _ = &mut closed_rx => {
+                        log::debug!("Connection {:?} closed by the server", client_id);
+                        break;
+                    }
+                }
+            }
+            // End the client's message stream even if the peer never
+            // acknowledges the close
+            drop(in_tx);
+            let close = async { ws_tx.lock().await.close().await };
+            let _ = time::timeout(heartbeat.timeout, close).await;
+        };
+
+        tokio::join!(self.run_client(client_id, tx, rx), reader);
+    }
+
+    /// Serve a client over any transport. Messages that failed to parse are
+    /// reported back to the client. Returns when `rx` ends. Game states are
+    /// serialized for WebSocket clients up front, and parsed again for `tx`.
+    pub async fn handle_client<Tx, Rx>(self, tx: Tx, rx: Rx)
+    where
+        Tx: Sink<ServerMessage> + Send + 'static,
+        Tx::Error: Into<Box<dyn Error + Send + Sync>>,
+        Rx: Stream<Item = Result<ClientMessage, serde_json::Error>>,
+    {
+        let tx = tx
+            .sink_map_err(Into::into)
+            .with(|message: Outgoing| future::ready(Ok::<_, SinkError>(message.into_message())));
+        self.run_client(ConnectionId::new(), tx, rx).await;
+    }
+
+    async fn run_client<Tx, Rx>(self, client_id: ConnectionId, tx: Tx, rx: Rx)
+    where
+        Tx: Sink<Outgoing> + Send + 'static,
+        Tx::Error: Into<SinkError>,
+        Rx: Stream<Item = Result<ClientMessage, serde_json::Error>>,
+    {
         log::debug!(
             "New connection from {:?} with client id {:?}",
             self.peer_addr,
             client_id
         );
 
-        let (tx, mut rx) = websocket.split();
-
+        let tx = ClientSink(Box::pin(tx.sink_map_err(Into::into)));
         self.server
             .event_tx
             .send(EventData::Connected(tx).finalize(client_id))
             .await
             .unwrap();
 
-        while let Some(body) = rx.next().await {
-            let message = match body {
-                Ok(msg) => msg,
-                Err(e) => {
-                    eprintln!("error reading message on websocket: {}", e);
-                    break;
-                }
+        tokio::pin!(rx);
+        while let Some(message) = rx.next().await {
+            let data = match message {
+                Ok(payload) => EventData::Message(payload),
+                Err(error) => EventData::InvalidMessage(error),
             };
-
-            // Skip non-text messages
-            if let Ok(msg) = message.to_str() {
-                match serde_json::from_str::<ClientMessage>(msg) {
-                    Ok(payload) => self
-                        .server
-                        .event_tx
-                        .send(EventData::Message(payload).finalize(client_id))
-                        .await
-                        .unwrap(),
-                    Err(error) => self
-                        .server
-                        .event_tx
-                        .send(EventData::InvalidMessage(error).finalize(client_id))
-                        .await
-                        .unwrap(),
-                }
-            }
+            self.server
+                .event_tx
+                .send(data.finalize(client_id))
+                .await
+                .unwrap();
         }
 
         self.server
@@ -1,27 +1,148 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{iter, mem};
 
+use serde::{Deserialize, Serialize};
 use tokio::time::Instant;
 use uuid::Uuid;
 
-use wgfw_protocol::{GameId, PlayerId};
+use wgfw_protocol::{
+    ChatLine, ErrorReply, GameId, GameResult, LobbyPhase, PlayerId, PlayerInfo, Profile, Rating,
+    SeedCommitment, TurnInfo,
+};
 
-use crate::{event_queue::EventQueue, game_server::PublishGameState};
+use crate::{
+    auth::Claims,
+    chat::LobbyChat,
+    clock::{Clock, SystemClock},
+    event_queue::EventQueue,
+    game_registry::GameModeInfo,
+    game_server::PublishGameState,
+    latency::{ConnectionQuality, PlayerConnection},
+    replay::{GameLog, Input, Recorder},
+    rng::{self, LobbyRng, Seed},
+    teams::Teams,
+};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(transparent)]
 pub struct EventId(Uuid);
 impl EventId {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self(Uuid::new_v4())
     }
 }
 
+/// When a scheduled event fires
+#[derive(Debug, Clone, Copy)]
+pub enum Timing {
+    At(Instant),
+    /// Relative to the lobby clock when the updates are applied
+    After(Duration),
+}
+
+/// A timer-delayed event
+#[derive(Debug, Clone)]
+pub struct ScheduledEvent {
+    pub id: EventId,
+    pub at: Timing,
+    /// Repeat with this interval until cancelled
+    pub period: Option<Duration>,
+    /// Passed back to `Game::on_event`
+    pub payload: serde_json::Value,
+}
+impl ScheduledEvent {
+    /// Next occurrence of a repeating event that fired at `at`, at least
+    /// [`MIN_INTERVAL`] later
+    pub(crate) fn repeat(&self, at: Instant) -> Option<(Instant, Self)> {
+        let next_at = at + self.period?.max(MIN_INTERVAL);
+        let next = Self {
+            at: Timing::At(next_at),
+            ..self.clone()
+        };
+        Some((next_at, next))
+    }
+}
+
+/// Who receives a notification
+#[derive(Debug, Clone)]
+pub enum Recipients {
+    All,
+    Players(Vec<PlayerId>),
+    AllExcept(Vec<PlayerId>),
+    /// Members of a team
+    Team(String),
+    /// Members that aren't on any team. Empty if the game mode has no teams;
+    /// use [`Recipients::AllExcept`] to leave out the seated players instead.
+    Spectators,
+}
+impl Recipients {
+    /// Lobby members matching the recipients
+    pub(crate) fn resolve(&self, common: &GameCommon) -> Vec<PlayerId> {
+        let mut players: Vec<PlayerId> = match self {
+            Self::All => common.players.iter().copied().collect(),
+            Self::Players(players) => players
+                .iter()
+                .filter(|player| common.players.contains(player))
+                .copied()
+                .collect(),
+            Self::AllExcept(excluded) => common
+                .players
+                .iter()
+                .filter(|player| !excluded.contains(player))
+                .copied()
+                .collect(),
+            Self::Team(team) => common
+                .teams
+                .members(team)
+                .into_iter()
+                .filter(|player| common.players.contains(player))
+                .collect(),
+            Self::Spectators if !common.teams.is_enabled() => Vec::new(),
+            Self::Spectators => common
+                .players
+                .iter()
+                .filter(|player| common.teams.team_of(**player).is_none())
+                .copied()
+                .collect(),
+        };
+        players.sort();
+        players.dedup();
+        players
+    }
+}
+
+/// One-off message to some of the players, e.g. for animations or sound effects
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub to: Recipients,
+    pub payload: serde_json::Value,
+}
+
 /// Updates needed after processing a message or event
 #[must_use]
 pub struct Updates {
-    /// Broadcast new state to all players
+    /// Public state changed, send new state to all players
     pub state_changed: bool,
+    /// Only the private state of these players changed. They get the public
+    /// state of the last broadcast, without recomputing it. While a ticking
+    /// lobby has a broadcast waiting for the next tick, they get that instead.
+    pub private_changed: Vec<PlayerId>,
     /// Schedule timer-delayed events
-    pub events: Vec<(Instant, EventId)>,
+    pub events: Vec<ScheduledEvent>,
+    /// Cancel previously scheduled events
+    pub cancelled: Vec<EventId>,
+    /// The game has ended, move the lobby to the finished phase
+    pub finished: bool,
+    /// Result to record and announce to the players
+    pub result: Option<GameResult>,
+    /// Change the tick rate of the lobby, in Hz. `Some(0)` stops ticking.
+    pub tick_rate: Option<u32>,
+    /// Notifications to send, in order
+    pub notifications: Vec<Notification>,
+    /// Lines posted to the lobby chat, sent to all members
+    pub(crate) chat: Vec<ChatLine>,
 }
 impl Updates {
     pub const CHANGED: Self = Self::new(true);
@@ -30,19 +151,109 @@ impl Updates {
     pub const fn new(state_changed: bool) -> Self {
         Self {
             state_changed,
+            private_changed: Vec::new(),
             events: Vec::new(),
+            cancelled: Vec::new(),
+            finished: false,
+            result: None,
+            tick_rate: None,
+            notifications: Vec::new(),
+            chat: Vec::new(),
         }
     }
 
     pub fn merge(mut self, other: Self) -> Self {
         self.state_changed |= other.state_changed;
+        self.private_changed.extend(other.private_changed);
         self.events.extend(other.events);
+        self.cancelled.extend(other.cancelled);
+        self.finished |= other.finished;
+        self.result = other.result.or(self.result);
+        self.tick_rate = other.tick_rate.or(self.tick_rate);
+        self.notifications.extend(other.notifications);
+        self.chat.extend(other.chat);
         self
     }
 
+    /// Private state of the given players changed, nothing else is visible
+    pub fn private_for(players: &[PlayerId]) -> Self {
+        let mut updates = Self::NONE;
+        updates.private_changed = players.to_vec();
+        updates
+    }
+
+    /// Send new state to a player whose private state changed
+    pub fn mark_private_changed(&mut self, player: PlayerId) {
+        self.private_changed.push(player);
+    }
+
     pub fn add_timeout(&mut self, at: Instant) -> EventId {
+        self.schedule(Timing::At(at), None, serde_json::Value::Null)
+    }
+
+    /// Schedule an event carrying a payload
+    pub fn add_timeout_with<T: Serialize>(&mut self, at: Instant, payload: &T) -> EventId {
+        self.schedule(Timing::At(at), None, to_payload(payload))
+    }
+
+    /// Schedule an event after a delay
+    pub fn add_delay(&mut self, delay: Duration) -> EventId {
+        self.schedule(Timing::After(delay), None, serde_json::Value::Null)
+    }
+
+    pub fn add_delay_with<T: Serialize>(&mut self, delay: Duration, payload: &T) -> EventId {
+        self.schedule(Timing::After(delay), None, to_payload(payload))
+    }
+
+    /// Schedule a repeating event, first fired after one period. Periods
+    /// shorter than [`MIN_INTERVAL`] are raised to it.
+    pub fn add_interval_with<T: Serialize>(&mut self, period: Duration, payload: &T) -> EventId {
+        let period = period.max(MIN_INTERVAL);
+        self.schedule(Timing::After(period), Some(period), to_payload(payload))
+    }
+
+    /// Start, stop (`0`) or change the fixed-rate tick loop of the lobby.
+    /// Rates above [`MAX_TICK_RATE`] are lowered to it.
+    pub fn set_tick_rate(mut self, hz: u32) -> Self {
+        self.tick_rate = Some(hz);
+        self
+    }
+
+    /// Send a notification to all lobby members
+    pub fn notify_all<T: Serialize>(&mut self, payload: &T) {
+        self.notify_to(Recipients::All, payload);
+    }
+
+    /// Send a notification to the listed players
+    pub fn notify<T: Serialize>(&mut self, players: &[PlayerId], payload: &T) {
+        self.notify_to(Recipients::Players(players.to_vec()), payload);
+    }
+
+    pub fn notify_to<T: Serialize>(&mut self, to: Recipients, payload: &T) {
+        self.notifications.push(Notification {
+            to,
+            payload: to_payload(payload),
+        });
+    }
+
+    /// Cancel a pending event. Cancelling an already fired event does nothing.
+    pub fn cancel(&mut self, id: EventId) {
+        self.cancelled.push(id);
+    }
+
+    fn schedule(
+        &mut self,
+        at: Timing,
+        period: Option<Duration>,
+        payload: serde_json::Value,
+    ) -> EventId {
         let id = EventId::new();
-        self.events.push((at, id));
+        self.events.push(ScheduledEvent {
+            id,
+            at,
+            period,
+            payload,
+        });
         id
     }
 
@@ -51,42 +262,86 @@ impl Updates {
         self
     }
 
+    /// End the running game
+    pub fn finish(mut self) -> Self {
+        self.finished = true;
+        self
+    }
+
+    /// Record a game result. Usually combined with `finish`.
+    pub fn with_result(mut self, result: GameResult) -> Self {
+        self.result = Some(result);
+        self
+    }
+
     pub(crate) fn apply(
         self,
         game_id: GameId,
         publish: &mut PublishGameState,
-        scheduled: &mut EventQueue<(GameId, EventId)>,
+        scheduled: &mut EventQueue<(GameId, EventId), ScheduledEvent>,
     ) {
         if self.state_changed {
             publish.add_all(game_id);
+        } else {
+            for player in self.private_changed {
+                publish.add(game_id, player);
+            }
         }
 
-        self.apply_schedule(game_id, scheduled);
-    }
+        if let Some(result) = self.result {
+            publish.add_result(game_id, result);
+        }
 
-    /// Returns true if the state changed and needs broadcasting
-    pub(crate) fn apply_schedule(
-        self,
-        game_id: GameId,
-        scheduled: &mut EventQueue<(GameId, EventId)>,
-    ) -> bool {
-        for (at, event_id) in self.events {
-            scheduled.add((game_id, event_id), at);
+        if let Some(hz) = self.tick_rate {
+            publish.set_tick_rate(game_id, hz);
+        }
+
+        for notification in self.notifications {
+            publish.add_notification(game_id, notification);
+        }
+
+        for line in self.chat {
+            publish.add_chat(game_id, line);
+        }
+
+        let now = scheduled.now();
+        for event in self.events {
+            let at = match event.at {
+                Timing::At(at) => at,
+                Timing::After(delay) => now + delay,
+            };
+            scheduled.add((game_id, event.id), event, at);
         }
 
-        self.state_changed
+        for event_id in self.cancelled {
+            scheduled.cancel(&(game_id, event_id));
+        }
     }
 }
 
+fn to_payload<T: Serialize>(payload: &T) -> serde_json::Value {
+    serde_json::to_value(payload).expect("Unable to serialize payload")
+}
+
 pub trait Game: Send + Sync {
     /// Extract public game state that is visible to all players
     fn public_state(&self, common: &GameCommon) -> serde_json::Value;
     /// Extract private game state that is only visible to a single player
     fn state_for_player(&self, common: &GameCommon, player: PlayerId) -> serde_json::Value;
+    /// Extract private game state that is shared by the members of a team
+    fn state_for_team(&self, _common: &GameCommon, _team: &str) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+
+    /// Turn order shown to clients, see [`crate::turns::Turns::info`]
+    fn turn_info(&self, _common: &GameCommon) -> Option<TurnInfo> {
+        None
+    }
 
     /// Does the game accept new players at the moment?
-    fn can_join(&self, _common: &GameCommon) -> bool {
-        true // Default to always allowing joins
+    fn can_join(&self, common: &GameCommon) -> bool {
+        // Default to allowing joins while the game isn't in progress
+        matches!(common.phase, LobbyPhase::Waiting | LobbyPhase::Finished)
     }
 
     /// Does the game accept reconnecting players at the moment?
@@ -94,6 +349,11 @@ pub trait Game: Send + Sync {
         true // Default to always allowing reconnects
     }
 
+    /// Is the built-in lobby chat available at the moment?
+    fn has_chat(&self, _common: &GameCommon) -> bool {
+        true
+    }
+
     fn on_disconnect(&mut self, _common: &GameCommon, _player: PlayerId) -> Updates {
         Updates::NONE
     }
@@ -110,8 +370,55 @@ pub trait Game: Send + Sync {
         Updates::NONE
     }
 
-    fn on_event(&mut self, _common: &GameCommon, _id: EventId) -> Updates {
-        panic!("No event handler defined, but an event was scheduled");
+    /// Fixed-rate tick, see [`Updates::set_tick_rate`]. `dt` is the tick period.
+    fn on_tick(&mut self, _common: &GameCommon, _dt: Duration) -> Updates {
+        Updates::NONE
+    }
+
+    /// The lobby moved to the running phase
+    fn on_start(&mut self, _common: &GameCommon) -> Updates {
+        Updates::NONE
+    }
+    /// The game reported that it has finished
+    fn on_finish(&mut self, _common: &GameCommon) -> Updates {
+        Updates::NONE
+    }
+
+    /// The connection quality of a player crossed a threshold set in
+    /// [`GameModeInfo::quality_thresholds`]
+    fn on_connection_quality(
+        &mut self,
+        _common: &GameCommon,
+        _player: PlayerId,
+        _quality: ConnectionQuality,
+    ) -> Updates {
+        Updates::NONE
+    }
+
+    /// Summary passed to the next game when the lobby switches game mode
+    fn handoff_summary(&self, _common: &GameCommon) -> Option<serde_json::Value> {
+        None
+    }
+    /// The lobby switched to this game mode from `previous_mode`, which left a summary.
+    /// Called after `on_join` for all members.
+    fn on_handoff(
+        &mut self,
+        _common: &GameCommon,
+        _previous_mode: &str,
+        _summary: serde_json::Value,
+    ) -> Updates {
+        Updates::NONE
+    }
+
+    /// A scheduled event fired. `payload` is `null` for events scheduled without one.
+    fn on_event(
+        &mut self,
+        _common: &GameCommon,
+        id: EventId,
+        _payload: serde_json::Value,
+    ) -> Updates {
+        log::warn!("No event handler defined, ignoring event {:?}", id);
+        Updates::NONE
     }
 
     fn on_message_from(
@@ -126,6 +433,135 @@ pub trait Game: Send + Sync {
 pub struct GameCommon {
     pub leader: PlayerId,
     pub players: HashSet<PlayerId>,
+    /// Verified claims of players that have authenticated externally
+    pub claims: HashMap<PlayerId, Claims>,
+    /// Profiles of players that have set one
+    pub profiles: HashMap<PlayerId, Profile>,
+    /// Built-in lobby chat
+    pub chat: LobbyChat,
+    pub phase: LobbyPhase,
+    /// Players that have confirmed readiness during a ready check
+    pub ready: HashSet<PlayerId>,
+    /// Fixed-rate tick loop, if running
+    pub ticker: Option<Ticker>,
+    /// Time source for game logic. Use instead of `Instant::now()`.
+    pub clock: Arc<dyn Clock>,
+    /// Presence, last-seen time and latency of members
+    pub connections: HashMap<PlayerId, PlayerConnection>,
+    /// Random numbers for game logic. Use instead of other sources of randomness.
+    pub rng: LobbyRng,
+    /// Published seed hash of the current game, with commit-reveal enabled
+    pub seed_commitment: Option<SeedCommitment>,
+    /// Team membership, if the game mode has teams
+    pub teams: Teams,
+    /// Members that are server-side bots
+    pub bots: HashSet<PlayerId>,
+    /// Ratings of human members in the current game mode, if it's rated.
+    /// Members whose rating failed to load are left out and not rated. Players
+    /// who leave a running game keep their entry until the next game starts,
+    /// so that leaving doesn't avoid a rated loss.
+    pub ratings: HashMap<PlayerId, Rating>,
+}
+impl GameCommon {
+    pub fn new(leader: PlayerId) -> Self {
+        Self {
+            leader,
+            players: iter::once(leader).collect(),
+            claims: HashMap::new(),
+            profiles: HashMap::new(),
+            chat: LobbyChat::default(),
+            phase: LobbyPhase::Waiting,
+            ready: HashSet::new(),
+            ticker: None,
+            clock: Arc::new(SystemClock),
+            connections: HashMap::new(),
+            rng: LobbyRng::new(),
+            seed_commitment: None,
+            teams: Teams::default(),
+            bots: HashSet::new(),
+            ratings: HashMap::new(),
+        }
+    }
+
+    /// Remove a player and all associated data. Returns false if not a member.
+    pub fn remove_player(&mut self, player: &PlayerId) -> bool {
+        if !self.players.remove(player) {
+            return false;
+        }
+        self.claims.remove(player);
+        self.profiles.remove(player);
+        self.ready.remove(player);
+        self.connections.remove(player);
+        self.teams.remove(*player);
+        self.bots.remove(player);
+        if self.phase != LobbyPhase::Running {
+            self.ratings.remove(player);
+        }
+        true
+    }
+
+    /// Player list as sent to clients, sorted by id
+    pub fn player_infos(&self) -> Vec<PlayerInfo> {
+        let mut players: Vec<_> = self
+            .players
+            .iter()
+            .map(|id| PlayerInfo {
+                id: *id,
+                profile: self.profiles.get(id).cloned(),
+                ready: self.ready.contains(id),
+                team: self.teams.team_of(*id).map(str::to_owned),
+                bot: self.bots.contains(id),
+                rating: self.ratings.get(id).copied(),
+                connected: self.connections.get(id).is_none_or(|c| c.connected),
+                last_seen: self.connections.get(id).and_then(|c| c.last_seen),
+            })
+            .collect();
+        players.sort_by_key(|info| info.id);
+        players
+    }
+}
+
+/// Shortest period of a repeating event
+pub const MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Highest tick rate in Hz, higher rates are lowered to it
+pub const MAX_TICK_RATE: u32 = 1000;
+
+/// Maximum number of late ticks run at once before skipping ahead
+const MAX_CATCH_UP_TICKS: u32 = 5;
+
+/// Fixed-rate tick state of a lobby
+#[derive(Debug, Clone)]
+pub struct Ticker {
+    pub period: Duration,
+    /// Number of ticks run so far
+    pub tick: u64,
+    /// Scheduled time of the next tick
+    pub next_at: Instant,
+    /// Wakeup scheduled for the next tick
+    pub(crate) wakeup: EventId,
+    /// State has changed since the last broadcast
+    pub(crate) dirty: bool,
+    /// Notifications waiting for the deferred broadcast
+    pub(crate) notifications: Vec<Notification>,
+}
+impl Ticker {
+    /// Ticker running at `hz`, continuing the tick count and the deferred
+    /// broadcast of `previous`. The rate is clamped to 1 ..= [`MAX_TICK_RATE`].
+    pub(crate) fn new(hz: u32, previous: Option<Ticker>, now: Instant) -> Self {
+        let period = Duration::from_secs(1) / hz.clamp(1, MAX_TICK_RATE);
+        let (tick, dirty, notifications) = previous
+            .map(|old| (old.tick, old.dirty, old.notifications))
+            .unwrap_or_default();
+        Self {
+            period,
+            tick,
+            next_at: now + period,
+            wakeup: EventId::new(),
+            dirty,
+            notifications,
+        }
+    }
 }
 
 pub struct Lobby {
@@ -133,21 +569,29 @@ pub struct Lobby {
     pub common: GameCommon,
     /// State specific to the current game type
     pub state: Box<dyn Game>,
+    /// Name of the current game mode
+    pub mode: String,
+    /// Constraints of the current game mode
+    pub mode_info: GameModeInfo,
+    /// Input log, if recording
+    pub recording: Option<Recorder>,
 }
 impl Lobby {
+    /// Remove a member. A leaving leader is replaced by a "random" human
+    /// member; bots never lead, so a lobby left with only bots has no leader
+    /// and should be closed, see [`Lobby::only_bots_left`].
     pub fn try_remove_player(&mut self, player: &PlayerId) -> bool {
-        let removed = self.common.players.remove(player);
+        let removed = self.common.remove_player(player);
         if !removed {
             return false;
         }
 
-        // Assign a "random" leader
         if *player == self.common.leader {
             let new_leader = self
                 .common
                 .players
                 .iter()
-                .next()
+                .find(|id| !self.common.bots.contains(id))
                 .copied()
                 .unwrap_or_else(PlayerId::new);
             self.common.leader = new_leader;
@@ -156,6 +600,11 @@ impl Lobby {
         true
     }
 
+    /// The lobby still has members, but all of them are bots
+    pub fn only_bots_left(&self) -> bool {
+        !self.common.players.is_empty() && self.common.players.is_subset(&self.common.bots)
+    }
+
     pub fn public_state(&self) -> serde_json::Value {
         self.state.public_state(&self.common)
     }
@@ -164,36 +613,332 @@ impl Lobby {
         self.state.state_for_player(&self.common, player)
     }
 
+    /// Team-shared state of the team of `player`, `null` if not in a team
+    pub fn state_for_team_of(&self, player: PlayerId) -> serde_json::Value {
+        match self.common.teams.team_of(player) {
+            Some(team) => self.state.state_for_team(&self.common, team),
+            None => serde_json::Value::Null,
+        }
+    }
+
+    pub fn turn_info(&self) -> Option<TurnInfo> {
+        self.state.turn_info(&self.common)
+    }
+
     pub fn can_join(&self) -> bool {
-        self.state.can_join(&self.common)
+        let is_full = self
+            .mode_info
+            .max_players
+            .is_some_and(|max| self.common.players.len() >= max);
+        !is_full && self.state.can_join(&self.common)
     }
 
     pub fn can_reconnect(&self) -> bool {
         self.state.can_reconnect(&self.common)
     }
 
+    pub fn has_chat(&self) -> bool {
+        self.state.has_chat(&self.common)
+    }
+
+    /// Start recording inputs from now on
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Recorder::new(&self.common, &self.mode));
+    }
+
+    /// Recorded input log. Refused while a commit-reveal game is running, as
+    /// the log contains the seed of the current game.
+    pub fn game_log(&self) -> Result<&GameLog, ErrorReply> {
+        let recording = self.recording.as_ref().ok_or(ErrorReply::NotRecorded)?;
+        if self.common.seed_commitment.is_some() && self.common.phase == LobbyPhase::Running {
+            return Err(ErrorReply::WrongPhase);
+        }
+        Ok(recording.log())
+    }
+
     pub fn on_disconnect(&mut self, player: PlayerId) -> Updates {
-        self.state.on_disconnect(&self.common, player)
+        let updates = self.state.on_disconnect(&self.common, player);
+        let updates = self.handle_finish(updates);
+        self.record(|_| Input::Disconnect(player), &updates);
+        updates
     }
 
     pub fn on_reconnect(&mut self, player: PlayerId) -> Updates {
-        self.state.on_reconnect(&self.common, player)
+        let updates = self.state.on_reconnect(&self.common, player);
+        let updates = self.handle_finish(updates);
+        self.record(|_| Input::Reconnect(player), &updates);
+        updates
     }
 
     pub fn on_join(&mut self, player: PlayerId) -> Updates {
-        self.state.on_join(&self.common, player)
+        self.common.teams.auto_assign(player);
+        let updates = self.state.on_join(&self.common, player);
+        let updates = self.handle_finish(updates);
+        self.record(
+            |common| Input::Join {
+                player,
+                bot: common.bots.contains(&player),
+                claims: common.claims.get(&player).cloned(),
+                profile: common.profiles.get(&player).cloned(),
+                rating: common.ratings.get(&player).copied(),
+            },
+            &updates,
+        );
+        updates
+    }
+
+    /// Join a bot, see [`crate::bots`]
+    pub(crate) fn add_bot(&mut self, bot: PlayerId, profile: Option<Profile>) -> Updates {
+        self.common.players.insert(bot);
+        self.common.bots.insert(bot);
+        self.common.connections.insert(bot, PlayerConnection::new());
+        if let Some(profile) = profile {
+            self.common.profiles.insert(bot, profile);
+        }
+        let updates = self.on_join(bot).always_publish();
+        updates.merge(self.post_chat(ChatLine::Joined(bot)))
+    }
+
+    /// Remove a member, human or bot, at the leader's request
+    pub(crate) fn kick(&mut self, player: PlayerId) -> Result<Updates, ErrorReply> {
+        if !self.try_remove_player(&player) {
+            return Err(ErrorReply::NoSuchPlayer);
+        }
+        let updates = self.on_kick(player).merge(self.check_ready());
+        Ok(updates
+            .always_publish()
+            .merge(self.post_chat(ChatLine::Kicked(player))))
+    }
+
+    /// Remove a member, human or bot, who left on their own
+    pub(crate) fn leave(&mut self, player: PlayerId) -> Result<Updates, ErrorReply> {
+        if !self.try_remove_player(&player) {
+            return Err(ErrorReply::NotInThatGame);
+        }
+        let updates = self.on_leave(player).merge(self.check_ready());
+        Ok(updates
+            .always_publish()
+            .merge(self.post_chat(ChatLine::Left(player))))
+    }
+
+    /// Post a message of a member to the lobby chat
+    pub(crate) fn send_chat(
+        &mut self,
+        sender: PlayerId,
+        text: String,
+    ) -> Result<Updates, ErrorReply> {
+        if !self.common.players.contains(&sender) {
+            return Err(ErrorReply::NotInThatGame);
+        }
+        if !self.has_chat() {
+            return Err(ErrorReply::ChatDisabled);
+        }
+        if self.common.chat.is_muted(&sender) {
+            return Err(ErrorReply::MutedInChat);
+        }
+        if text.chars().count() > ChatLine::MAX_MESSAGE_LEN {
+            return Err(ErrorReply::ChatMessageTooLong);
+        }
+        Ok(self.post_chat(ChatLine::Message { sender, text }))
+    }
+
+    /// Retained chat lines, as seen by a member
+    pub(crate) fn chat_history(&self, player: PlayerId) -> Result<Vec<ChatLine>, ErrorReply> {
+        if !self.common.players.contains(&player) {
+            return Err(ErrorReply::NotInThatGame);
+        }
+        if !self.has_chat() {
+            return Err(ErrorReply::ChatDisabled);
+        }
+        Ok(self.common.chat.history().cloned().collect())
+    }
+
+    /// Mute or unmute a member in the lobby chat, at the leader's request
+    pub(crate) fn set_chat_muted(
+        &mut self,
+        target: PlayerId,
+        muted: bool,
+    ) -> Result<Updates, ErrorReply> {
+        if !self.common.players.contains(&target) {
+            return Err(ErrorReply::NoSuchPlayer);
+        }
+        let changed = if muted {
+            self.common.chat.muted.insert(target)
+        } else {
+            self.common.chat.muted.remove(&target)
+        };
+        if !changed {
+            return Ok(Updates::NONE);
+        }
+        let line = if muted {
+            ChatLine::Muted(target)
+        } else {
+            ChatLine::Unmuted(target)
+        };
+        Ok(self.post_chat(line))
+    }
+
+    /// Add a line to the lobby chat, unless the game has disabled it
+    pub(crate) fn post_chat(&mut self, line: ChatLine) -> Updates {
+        let mut updates = Updates::NONE;
+        if self.has_chat() {
+            self.common.chat.push(line.clone());
+            updates.chat.push(line);
+        }
+        updates
     }
 
     pub fn on_leave(&mut self, player: PlayerId) -> Updates {
-        self.state.on_leave(&self.common, player)
+        let updates = self.state.on_leave(&self.common, player);
+        let updates = self.handle_finish(updates);
+        let leader = self.common.leader;
+        self.record(|_| Input::Leave { player, leader }, &updates);
+        updates
     }
 
     pub fn on_kick(&mut self, player: PlayerId) -> Updates {
-        self.state.on_kick(&self.common, player)
+        let updates = self.state.on_kick(&self.common, player);
+        let updates = self.handle_finish(updates);
+        let leader = self.common.leader;
+        self.record(|_| Input::Kick { player, leader }, &updates);
+        updates
+    }
+
+    /// Move a player to a team, or out of all teams with `None`
+    pub fn set_team(
+        &mut self,
+        player: PlayerId,
+        team: Option<String>,
+    ) -> Result<Updates, ErrorReply> {
+        if matches!(
+            self.common.phase,
+            LobbyPhase::ReadyCheck | LobbyPhase::Running
+        ) {
+            return Err(ErrorReply::WrongPhase);
+        }
+
+        match &team {
+            Some(name) => self.common.teams.assign(player, name)?,
+            None => {
+                self.common.teams.remove(player);
+            }
+        }
+
+        let updates = Updates::CHANGED;
+        self.record(|_| Input::Team(player, team), &updates);
+        Ok(updates)
+    }
+
+    /// A member lost the connection, but stays in the lobby
+    pub(crate) fn disconnect(&mut self, player: PlayerId) -> Updates {
+        let now = self.common.clock.unix_time();
+        if let Some(connection) = self.common.connections.get_mut(&player) {
+            connection.disconnect(now);
+        }
+        self.on_disconnect(player)
+    }
+
+    /// Replace the connection state of a member after a new measurement,
+    /// telling the game if the connection quality changed
+    pub(crate) fn update_connection(
+        &mut self,
+        player: PlayerId,
+        connection: PlayerConnection,
+    ) -> Updates {
+        let previous = self.common.connections.insert(player, connection);
+        self.quality_changed(player, previous)
     }
 
-    pub fn on_event(&mut self, id: EventId) -> Updates {
-        self.state.on_event(&self.common, id)
+    /// A member is back on a new connection. The game is told about the
+    /// reconnect, and then about the quality change from the old connection.
+    pub(crate) fn reconnect(&mut self, player: PlayerId, connection: PlayerConnection) -> Updates {
+        let previous = self.common.connections.insert(player, connection);
+        let updates = self.on_reconnect(player);
+        updates.merge(self.quality_changed(player, previous))
+    }
+
+    fn quality_changed(&mut self, player: PlayerId, previous: Option<PlayerConnection>) -> Updates {
+        let Some(thresholds) = self.mode_info.quality_thresholds else {
+            return Updates::NONE;
+        };
+        let Some(connection) = self.common.connections.get(&player) else {
+            return Updates::NONE;
+        };
+        let quality = connection.quality(thresholds);
+        let previous = previous.map(|c| c.quality(thresholds)).unwrap_or_default();
+        if previous == quality {
+            return Updates::NONE;
+        }
+        self.on_connection_quality(player, quality)
+    }
+
+    /// Make another member the leader. Bots can't lead.
+    pub fn promote(&mut self, player: PlayerId) -> Result<Updates, ErrorReply> {
+        if !self.common.players.contains(&player) {
+            return Err(ErrorReply::NoSuchPlayer);
+        }
+        if self.common.bots.contains(&player) {
+            return Err(ErrorReply::BotCannotLead);
+        }
+
+        self.common.leader = player;
+        let updates = Updates::CHANGED;
+        self.record(|_| Input::Promote(player), &updates);
+        Ok(updates)
+    }
+
+    /// Show a new profile of a member
+    pub fn set_profile(&mut self, player: PlayerId, profile: Profile) -> Updates {
+        self.common.profiles.insert(player, profile.clone());
+        let updates = Updates::CHANGED;
+        self.record(|_| Input::Profile(player, profile), &updates);
+        updates
+    }
+
+    /// Replace the ratings of the members, e.g. after switching game modes
+    pub fn set_ratings(&mut self, ratings: HashMap<PlayerId, Rating>) -> Updates {
+        self.common.ratings = ratings;
+        let updates = Updates::CHANGED;
+        self.record(
+            |common| {
+                let mut ratings: Vec<_> = common
+                    .ratings
+                    .iter()
+                    .map(|(id, rating)| (*id, *rating))
+                    .collect();
+                ratings.sort_by_key(|(player, _)| *player);
+                Input::Ratings(ratings)
+            },
+            &updates,
+        );
+        updates
+    }
+
+    pub fn on_connection_quality(
+        &mut self,
+        player: PlayerId,
+        quality: ConnectionQuality,
+    ) -> Updates {
+        let updates = self
+            .state
+            .on_connection_quality(&self.common, player, quality);
+        let updates = self.handle_finish(updates);
+        self.record(|_| Input::ConnectionQuality(player, quality), &updates);
+        updates
+    }
+
+    pub fn on_event(&mut self, id: EventId, payload: serde_json::Value) -> Updates {
+        let recorded = self.recording.is_some().then(|| payload.clone());
+        let updates = self.state.on_event(&self.common, id, payload);
+        let updates = self.handle_finish(updates);
+        self.record(
+            |_| Input::Event {
+                id,
+                payload: recorded.unwrap(),
+            },
+            &updates,
+        );
+        updates
     }
 
     pub fn on_message_from(
@@ -201,6 +946,338 @@ impl Lobby {
         player: PlayerId,
         message: serde_json::Value,
     ) -> (Updates, Result<serde_json::Value, serde_json::Value>) {
-        self.state.on_message_from(&self.common, player, message)
+        let recorded = self.recording.is_some().then(|| message.clone());
+        let (updates, reply) = self.state.on_message_from(&self.common, player, message);
+        let updates = self.handle_finish(updates);
+        self.record(
+            |_| Input::Message {
+                player,
+                message: recorded.unwrap(),
+            },
+            &updates,
+        );
+        (updates, reply)
+    }
+
+    /// Run all ticks due by `now`. Returned updates request a broadcast if
+    /// anything changed since the last tick.
+    pub fn run_ticks(&mut self, now: Instant) -> Updates {
+        let mut updates = Updates::NONE;
+        let mut count = 0;
+        for _ in 0..MAX_CATCH_UP_TICKS {
+            if self.common.ticker.as_ref().is_none_or(|t| t.next_at > now) {
+                break;
+            }
+            updates = updates.merge(self.tick());
+            count += 1;
+        }
+
+        if let Some(ticker) = self.common.ticker.as_mut() {
+            if ticker.next_at <= now {
+                let behind = (now - ticker.next_at).as_nanos() / ticker.period.as_nanos() + 1;
+                log::warn!("Tick loop overrun, skipping {} ticks", behind);
+                ticker.next_at = now + ticker.period;
+            }
+
+            updates.state_changed |= mem::take(&mut ticker.dirty);
+            if !ticker.notifications.is_empty() {
+                let later = mem::replace(
+                    &mut updates.notifications,
+                    mem::take(&mut ticker.notifications),
+                );
+                updates.notifications.extend(later);
+            }
+        }
+
+        let updates = self.handle_finish(updates);
+        if count > 0 {
+            self.record(|_| Input::Ticks(count), &updates);
+        }
+        updates
+    }
+
+    /// Run ticks back to back, regardless of time
+    pub(crate) fn run_tick_count(&mut self, count: u32) -> Updates {
+        let mut updates = Updates::NONE;
+        for _ in 0..count {
+            updates = updates.merge(self.tick());
+        }
+        self.handle_finish(updates)
+    }
+
+    fn tick(&mut self) -> Updates {
+        let Some(ticker) = self.common.ticker.as_mut() else {
+            return Updates::NONE;
+        };
+        ticker.tick += 1;
+        ticker.next_at += ticker.period;
+        let dt = ticker.period;
+        self.state.on_tick(&self.common, dt)
+    }
+
+    /// Start, stop (`0`) or change the tick loop, continuing the tick count
+    pub(crate) fn set_tick_rate(&mut self, hz: u32) {
+        let old = self.common.ticker.take();
+        if hz != 0 {
+            let now = self.common.clock.now();
+            self.common.ticker = Some(Ticker::new(hz, old, now));
+        }
+    }
+
+    /// Whether a broadcast to all members waits for the next tick. Only
+    /// ticking lobbies coalesce, and never the broadcast of a tick itself.
+    pub(crate) fn defer_broadcast(&mut self, ticked: bool) -> bool {
+        match self.common.ticker.as_mut() {
+            Some(ticker) if !ticked => {
+                ticker.dirty = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether a broadcast to all members is waiting for the next tick. State
+    /// updates of single players are left to it, as the cached public state
+    /// they would be sent with is stale.
+    pub(crate) fn broadcast_pending(&self) -> bool {
+        self.common
+            .ticker
+            .as_ref()
+            .is_some_and(|ticker| ticker.dirty)
+    }
+
+    /// Hold back a notification while a broadcast waits for the next tick, so
+    /// that it isn't sent before the state it refers to. Returns the
+    /// notification if it can be sent now.
+    pub(crate) fn defer_notification(
+        &mut self,
+        notification: Notification,
+        ticked: bool,
+    ) -> Option<Notification> {
+        match self.common.ticker.as_mut() {
+            Some(ticker) if ticker.dirty && !ticked => {
+                ticker.notifications.push(notification);
+                None
+            }
+            _ => Some(notification),
+        }
+    }
+
+    /// Replace the game with a new game mode, keeping the members and leader.
+    /// The tick loop is stopped; scheduled events and the wakeup of the old
+    /// tick loop must be cancelled by the caller.
+    pub fn change_mode(
+        &mut self,
+        mode: String,
+        state: Box<dyn Game>,
+        mode_info: GameModeInfo,
+    ) -> Result<Updates, ErrorReply> {
+        if matches!(
+            self.common.phase,
+            LobbyPhase::ReadyCheck | LobbyPhase::Running
+        ) {
+            return Err(ErrorReply::WrongPhase);
+        }
+        if mode_info
+            .max_players
+            .is_some_and(|max| self.common.players.len() > max)
+        {
+            return Err(ErrorReply::TooManyPlayers);
+        }
+
+        let summary = self.state.handoff_summary(&self.common);
+        let previous_mode = mem::replace(&mut self.mode, mode.clone());
+        self.state = state;
+        self.mode_info = mode_info;
+        self.common.phase = LobbyPhase::Waiting;
+        self.common.ready.clear();
+        self.common.seed_commitment = None;
+        self.common.ticker = None;
+        self.common.teams = Teams::new(self.mode_info.teams.clone());
+
+        let mut members: Vec<PlayerId> = self.common.players.iter().copied().collect();
+        members.sort();
+        let mut updates = Updates::CHANGED;
+        for player in members {
+            self.common.teams.auto_assign(player);
+            updates = updates.merge(self.state.on_join(&self.common, player));
+        }
+        if let Some(summary) = summary {
+            updates = updates.merge(self.state.on_handoff(&self.common, &previous_mode, summary));
+        }
+
+        let updates = self.handle_finish(updates);
+        self.record(|_| Input::ChangeMode(mode), &updates);
+        Ok(updates)
+    }
+
+    /// Leader-triggered start, either directly or through a ready check
+    pub fn start(&mut self) -> Result<Updates, ErrorReply> {
+        if !matches!(
+            self.common.phase,
+            LobbyPhase::Waiting | LobbyPhase::Finished
+        ) {
+            return Err(ErrorReply::WrongPhase);
+        }
+
+        let count = self.common.players.len();
+        if count < self.mode_info.min_players {
+            return Err(ErrorReply::NotEnoughPlayers);
+        }
+        if self.mode_info.max_players.is_some_and(|max| count > max) {
+            return Err(ErrorReply::TooManyPlayers);
+        }
+
+        if self.mode_info.ready_check {
+            self.common.phase = LobbyPhase::ReadyCheck;
+            self.common.ready = self.common.bots.clone();
+            let updates = Updates::CHANGED;
+            self.record(|_| Input::ReadyCheck, &updates);
+            Ok(updates)
+        } else {
+            Ok(self.begin())
+        }
+    }
+
+    pub fn set_ready(&mut self, player: PlayerId, ready: bool) -> Result<Updates, ErrorReply> {
+        if self.common.phase != LobbyPhase::ReadyCheck {
+            return Err(ErrorReply::WrongPhase);
+        }
+
+        if ready {
+            self.common.ready.insert(player);
+        } else {
+            self.common.ready.remove(&player);
+        }
+        self.record(|_| Input::Ready(player, ready), &Updates::NONE);
+
+        Ok(self.check_ready().always_publish())
+    }
+
+    /// Re-evaluate a pending ready check, e.g. after someone has left. Bots
+    /// are always ready.
+    pub fn check_ready(&mut self) -> Updates {
+        if self.common.phase != LobbyPhase::ReadyCheck {
+            return Updates::NONE;
+        }
+
+        if self.common.players.len() < self.mode_info.min_players {
+            self.cancel_ready_check();
+            let updates = Updates::CHANGED;
+            self.record(|_| Input::CancelReadyCheck, &updates);
+            updates
+        } else if self
+            .common
+            .players
+            .iter()
+            .all(|id| self.common.ready.contains(id) || self.common.bots.contains(id))
+        {
+            self.begin()
+        } else {
+            Updates::NONE
+        }
+    }
+
+    /// Back to waiting, e.g. when too few players are left for the game
+    pub(crate) fn cancel_ready_check(&mut self) {
+        self.common.phase = LobbyPhase::Waiting;
+        self.common.ready.clear();
+    }
+
+    pub(crate) fn begin(&mut self) -> Updates {
+        self.begin_with(None)
+    }
+
+    /// Start a game, with the game seed of a replayed log
+    pub(crate) fn begin_with(&mut self, game_seed: Option<Seed>) -> Updates {
+        self.common.phase = LobbyPhase::Running;
+        self.common.ready.clear();
+        let players = &self.common.players;
+        self.common.ratings.retain(|id, _| players.contains(id));
+        match game_seed {
+            Some(seed) => self.common.rng.start_game_with(seed),
+            None => self.common.rng.start_game(),
+        }
+        self.common.seed_commitment = self.mode_info.commit_reveal.then(|| SeedCommitment {
+            hash: rng::to_hex(&rng::sha256(&self.common.rng.game_seed())),
+            seed: None,
+        });
+        let updates = self.state.on_start(&self.common);
+        let updates = self.handle_finish(updates).always_publish();
+        let game_seed = self.common.rng.game_seed();
+        self.record(|_| Input::Start(game_seed), &updates);
+        updates
+    }
+
+    fn record(&mut self, input: impl FnOnce(&GameCommon) -> Input, updates: &Updates) {
+        if let Some(recording) = &mut self.recording {
+            let state = self.state.public_state(&self.common);
+            let input = input(&self.common);
+            recording.record(self.common.clock.now(), input, updates, &state);
+        }
+    }
+
+    /// Moves the lobby to the finished phase if the game says so
+    fn handle_finish(&mut self, mut updates: Updates) -> Updates {
+        if updates.finished && self.common.phase == LobbyPhase::Running {
+            self.common.phase = LobbyPhase::Finished;
+            if let Some(commitment) = &mut self.common.seed_commitment {
+                commitment.seed = Some(rng::to_hex(&self.common.rng.game_seed()));
+            }
+            updates = updates
+                .merge(self.state.on_finish(&self.common))
+                .always_publish();
+        }
+        updates.finished = false;
+        updates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wgfw_protocol::TeamInfo;
+
+    use super::*;
+
+    /// Lobby of four members, the first two on teams "red" and "blue"
+    fn lobby() -> (GameCommon, Vec<PlayerId>) {
+        let mut players: Vec<PlayerId> = (0..4).map(|_| PlayerId::new()).collect();
+        players.sort();
+        let mut common = GameCommon::new(players[0]);
+        common.players.extend(&players);
+        common.teams = Teams::new(vec![
+            TeamInfo::new("red", None),
+            TeamInfo::new("blue", None),
+        ]);
+        common.teams.assign(players[0], "red").unwrap();
+        common.teams.assign(players[1], "blue").unwrap();
+        (common, players)
+    }
+
+    #[test]
+    fn recipients_are_sorted_members() {
+        let (common, players) = lobby();
+        let outsider = PlayerId::new();
+
+        assert_eq!(Recipients::All.resolve(&common), players);
+        let listed = Recipients::Players(vec![players[2], outsider, players[1], players[2]]);
+        assert_eq!(listed.resolve(&common), vec![players[1], players[2]]);
+        let except = Recipients::AllExcept(vec![players[0], outsider]);
+        assert_eq!(except.resolve(&common), players[1..]);
+    }
+
+    #[test]
+    fn recipients_by_team() {
+        let (mut common, players) = lobby();
+
+        assert_eq!(
+            Recipients::Team("red".into()).resolve(&common),
+            vec![players[0]]
+        );
+        assert!(Recipients::Team("green".into()).resolve(&common).is_empty());
+        assert_eq!(Recipients::Spectators.resolve(&common), players[2..]);
+
+        common.teams = Teams::default();
+        assert!(Recipients::Spectators.resolve(&common).is_empty());
     }
 }
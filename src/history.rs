@@ -0,0 +1,292 @@
+//! Storage for results of finished games
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use wgfw_protocol::{MatchRecord, PlayerId};
+
+pub type StoreResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// Persistent match history. Called from the server task, so keep operations short.
+pub trait MatchStore: Send + Sync {
+    fn save(&self, record: &MatchRecord) -> StoreResult<()>;
+    /// Past games of a player, most recent first
+    fn history(&self, player: PlayerId, limit: usize) -> StoreResult<Vec<MatchRecord>>;
+}
+
+/// Appends records to a file, one JSON object per line. The file is
+/// indexed by player on the first history query, so that queries read only
+/// the returned records.
+pub struct FileMatchStore {
+    path: PathBuf,
+    /// File offsets of the records of each player, oldest first, once the
+    /// file has been indexed. Also serializes appends.
+    index: Mutex<Option<HashMap<PlayerId, Vec<u64>>>>,
+}
+impl FileMatchStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            index: Mutex::new(None),
+        }
+    }
+
+    fn build_index(&self) -> StoreResult<HashMap<PlayerId, Vec<u64>>> {
+        let mut index: HashMap<PlayerId, Vec<u64>> = HashMap::new();
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(index),
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut reader = BufReader::new(file);
+        let mut offset = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let len = reader.read_line(&mut line)?;
+            if len == 0 {
+                break;
+            }
+            match serde_json::from_str::<MatchRecord>(&line) {
+                Ok(record) => {
+                    for player in record.players {
+                        index.entry(player).or_default().push(offset);
+                    }
+                }
+                Err(error) => log::warn!("Skipping invalid line in {:?}: {}", self.path, error),
+            }
+            offset += len as u64;
+        }
+        Ok(index)
+    }
+}
+
+impl MatchStore for FileMatchStore {
+    fn save(&self, record: &MatchRecord) -> StoreResult<()> {
+        let mut index = self.index.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let offset = file.seek(SeekFrom::End(0))?;
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        if let Some(index) = &mut *index {
+            for player in &record.players {
+                index.entry(*player).or_default().push(offset);
+            }
+        }
+        Ok(())
+    }
+
+    fn history(&self, player: PlayerId, limit: usize) -> StoreResult<Vec<MatchRecord>> {
+        let mut index = self.index.lock().unwrap();
+        let index = match &mut *index {
+            Some(index) => index,
+            None => index.insert(self.build_index()?),
+        };
+        let Some(offsets) = index.get(&player) else {
+            return Ok(Vec::new());
+        };
+
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut records = Vec::new();
+        let mut line = String::new();
+        for offset in offsets.iter().rev().take(limit) {
+            reader.seek(SeekFrom::Start(*offset))?;
+            line.clear();
+            reader.read_line(&mut line)?;
+            records.push(serde_json::from_str(&line)?);
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteMatchStore;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    use rusqlite::{params, Connection};
+
+    use wgfw_protocol::{MatchRecord, PlayerId};
+
+    use super::{MatchStore, StoreResult};
+
+    /// Stores records in an SQLite database
+    pub struct SqliteMatchStore {
+        conn: Mutex<Connection>,
+    }
+    impl SqliteMatchStore {
+        pub fn open(path: impl AsRef<Path>) -> StoreResult<Self> {
+            let conn = Connection::open(path)?;
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS matches (
+                    id INTEGER PRIMARY KEY,
+                    game_id TEXT NOT NULL,
+                    finished_at INTEGER NOT NULL,
+                    record TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS match_players (
+                    match_id INTEGER NOT NULL REFERENCES matches(id),
+                    player_id TEXT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS match_players_player
+                    ON match_players (player_id);",
+            )?;
+            Ok(Self {
+                conn: Mutex::new(conn),
+            })
+        }
+    }
+
+    impl MatchStore for SqliteMatchStore {
+        fn save(&self, record: &MatchRecord) -> StoreResult<()> {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO matches (game_id, finished_at, record) VALUES (?1, ?2, ?3)",
+                params![
+                    serde_json::to_string(&record.game_id)?,
+                    record.finished_at,
+                    serde_json::to_string(record)?,
+                ],
+            )?;
+            let match_id = tx.last_insert_rowid();
+            for player in &record.players {
+                tx.execute(
+                    "INSERT INTO match_players (match_id, player_id) VALUES (?1, ?2)",
+                    params![match_id, serde_json::to_string(player)?],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        }
+
+        fn history(&self, player: PlayerId, limit: usize) -> StoreResult<Vec<MatchRecord>> {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare(
+                "SELECT m.record FROM matches m
+                    JOIN match_players p ON p.match_id = m.id
+                    WHERE p.player_id = ?1
+                    ORDER BY m.finished_at DESC, m.id DESC
+                    LIMIT ?2",
+            )?;
+            let rows = stmt.query_map(
+                params![serde_json::to_string(&player)?, limit as i64],
+                |row| row.get::<_, String>(0),
+            )?;
+
+            let mut records = Vec::new();
+            for row in rows {
+                records.push(serde_json::from_str(&row?)?);
+            }
+            Ok(records)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use wgfw_protocol::{GameId, GameResult};
+
+    use super::*;
+
+    fn record(players: &[PlayerId], finished_at: u64) -> MatchRecord {
+        MatchRecord {
+            game_id: GameId::new(),
+            mode: "test".to_owned(),
+            finished_at,
+            players: players.to_vec(),
+            result: GameResult::default(),
+        }
+    }
+
+    #[test]
+    fn file_history_skips_invalid_lines() {
+        let path = std::env::temp_dir().join(format!("wgfw-history-{:?}.jsonl", GameId::new()));
+        fs::write(&path, "not json\n").unwrap();
+        let me = PlayerId::new();
+        let other = PlayerId::new();
+
+        let store = FileMatchStore::new(&path);
+        store.save(&record(&[me, other], 1)).unwrap();
+        store.save(&record(&[other], 2)).unwrap();
+        assert_eq!(store.history(me, 10).unwrap().len(), 1);
+        store.save(&record(&[me], 3)).unwrap();
+        store.save(&record(&[me, other], 4)).unwrap();
+
+        let reopened = FileMatchStore::new(&path);
+        for store in [&store, &reopened] {
+            let finished: Vec<u64> = store
+                .history(me, 2)
+                .unwrap()
+                .iter()
+                .map(|record| record.finished_at)
+                .collect();
+            assert_eq!(finished, [4, 3]);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn finished(store: &dyn MatchStore, player: PlayerId, limit: usize) -> Vec<u64> {
+        store
+            .history(player, limit)
+            .unwrap()
+            .iter()
+            .map(|record| record.finished_at)
+            .collect()
+    }
+
+    #[test]
+    fn file_history_pages_across_reopen() {
+        let path = std::env::temp_dir().join(format!("wgfw-history-{:?}.jsonl", GameId::new()));
+        let me = PlayerId::new();
+        let store = FileMatchStore::new(&path);
+        for finished_at in 1..=5 {
+            store.save(&record(&[me], finished_at)).unwrap();
+        }
+        drop(store);
+
+        let reopened = FileMatchStore::new(&path);
+        assert_eq!(finished(&reopened, me, 3), [5, 4, 3]);
+        reopened.save(&record(&[me], 6)).unwrap();
+        assert_eq!(finished(&reopened, me, 2), [6, 5]);
+        assert_eq!(finished(&reopened, me, 10), [6, 5, 4, 3, 2, 1]);
+        assert!(finished(&reopened, me, 0).is_empty());
+        assert!(finished(&reopened, PlayerId::new(), 10).is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_history_is_newest_first_per_player() {
+        let path = std::env::temp_dir().join(format!("wgfw-history-{:?}.sqlite", GameId::new()));
+        let me = PlayerId::new();
+        let other = PlayerId::new();
+        let store = SqliteMatchStore::open(&path).unwrap();
+        store.save(&record(&[me, other], 2)).unwrap();
+        store.save(&record(&[other], 3)).unwrap();
+        store.save(&record(&[me], 1)).unwrap();
+        store.save(&record(&[me], 4)).unwrap();
+        drop(store);
+
+        let reopened = SqliteMatchStore::open(&path).unwrap();
+        assert_eq!(finished(&reopened, me, 10), [4, 2, 1]);
+        assert_eq!(finished(&reopened, me, 2), [4, 2]);
+        assert_eq!(finished(&reopened, other, 10), [3, 2]);
+        assert!(finished(&reopened, PlayerId::new(), 10).is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+//! Presence and connection quality of players, measured by the WebSocket heartbeat
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Coarse connection quality, see [`QualityThresholds`]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Deserialize, Serialize,
+)]
+pub enum ConnectionQuality {
+    #[default]
+    Good,
+    Poor,
+    Bad,
+}
+
+/// Smoothed round-trip time limits for the quality levels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QualityThresholds {
+    /// At or above this the connection is poor
+    pub poor: Duration,
+    /// At or above this the connection is bad
+    pub bad: Duration,
+}
+
+/// Connection state of a lobby member
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerConnection {
+    pub connected: bool,
+    /// Unix timestamp in seconds of the disconnect, `None` while connected
+    pub last_seen: Option<u64>,
+    /// Smoothed round-trip time, `None` before the first measurement
+    pub rtt: Option<Duration>,
+    /// Smoothed variation of the round-trip time
+    pub jitter: Duration,
+}
+
+impl Default for PlayerConnection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PlayerConnection {
+    /// Connected, not measured yet
+    pub fn new() -> Self {
+        Self {
+            connected: true,
+            last_seen: None,
+            rtt: None,
+            jitter: Duration::ZERO,
+        }
+    }
+
+    /// Lost the connection at the Unix timestamp `at`
+    pub(crate) fn disconnect(&mut self, at: u64) {
+        self.connected = false;
+        self.last_seen = Some(at);
+    }
+
+    /// Add a measurement, smoothed as in TCP (RFC 6298)
+    pub(crate) fn sample(&mut self, rtt: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(rtt);
+                self.jitter = rtt / 2;
+            }
+            Some(srtt) => {
+                let deviation = srtt.abs_diff(rtt);
+                self.jitter = (self.jitter * 3 + deviation) / 4;
+                self.rtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+    }
+
+    /// Quality level by smoothed round-trip time. Unmeasured connections are good.
+    pub fn quality(&self, thresholds: QualityThresholds) -> ConnectionQuality {
+        match self.rtt {
+            Some(rtt) if rtt >= thresholds.bad => ConnectionQuality::Bad,
+            Some(rtt) if rtt >= thresholds.poor => ConnectionQuality::Poor,
+            _ => ConnectionQuality::Good,
+        }
+    }
+}
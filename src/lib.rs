@@ -1,25 +1,49 @@
 #![deny(unused_must_use)]
 
 use std::net::SocketAddr;
+use std::time::Duration;
 
+use auth::Authenticator;
+use bots::Bot;
+use clock::Clock;
 use game_state::Game;
+use history::MatchStore;
+use rating::RatingStore;
 use tokio::task::JoinHandle;
 use warp::{Filter, Rejection, Reply};
 
+pub mod auth;
+pub mod bots;
+pub mod chat;
+pub mod clock;
 mod event_queue;
 mod game_registry;
 mod game_server;
 pub mod game_state;
-
-pub use self::game_registry::GameRegistry;
+pub mod history;
+pub mod latency;
+mod matchmaking;
+pub mod rating;
+pub mod replay;
+pub mod rng;
+pub mod teams;
+pub mod testing;
+pub mod turns;
+
+pub use self::game_registry::{GameModeInfo, GameRegistry};
+pub use self::game_server::{ClientHandle, ServerRemote};
 pub use wgfw_protocol as protocol;
-pub use wgfw_protocol::{GameId, PlayerId, ReconnectionSecret};
+pub use wgfw_protocol::{GameId, PlayerId, Profile, ReconnectionSecret};
 
-use self::game_server::{ClientHandle, ServerRemote};
+use self::game_server::{Heartbeat, Settings};
 
 #[derive(Default)]
 pub struct Builder {
     registry: GameRegistry,
+    authenticator: Option<Box<dyn Authenticator>>,
+    match_store: Option<Box<dyn MatchStore>>,
+    rating_store: Option<Box<dyn RatingStore>>,
+    settings: Settings,
 }
 
 impl Builder {
@@ -33,6 +57,17 @@ impl Builder {
         self
     }
 
+    /// Register a game mode with player count limits and start options
+    pub fn register_with_info<G: Game + Default + 'static>(
+        mut self,
+        name: &str,
+        info: GameModeInfo,
+    ) -> Self {
+        self.registry
+            .register_with_info(name, Box::new(|| Box::<G>::default()), info);
+        self
+    }
+
     pub fn register_by_contructor(
         mut self,
         name: &str,
@@ -42,14 +77,94 @@ impl Builder {
         self
     }
 
+    /// Let lobby leaders add bots to games of the mode `name`. Bots act
+    /// `think_delay` after each state change.
+    pub fn register_bot<B: Bot + Default + 'static>(
+        mut self,
+        name: &str,
+        think_delay: Duration,
+    ) -> Self {
+        self.registry
+            .register_bot(name, Box::new(|| Box::<B>::default()), think_delay);
+        self
+    }
+
+    /// Allow players to identify using externally issued tokens
+    pub fn authenticator<A: Authenticator + 'static>(mut self, authenticator: A) -> Self {
+        self.authenticator = Some(Box::new(authenticator));
+        self
+    }
+
+    /// Persist results of finished games and enable match history queries
+    pub fn match_store<S: MatchStore + 'static>(mut self, store: S) -> Self {
+        self.match_store = Some(Box::new(store));
+        self
+    }
+
+    /// Persist ratings of rated game modes. Without a store, ratings are kept
+    /// in memory.
+    pub fn rating_store<S: RatingStore + 'static>(mut self, store: S) -> Self {
+        self.rating_store = Some(Box::new(store));
+        self
+    }
+
+    /// Number of lobby chat lines retained for each lobby
+    pub fn chat_history_limit(mut self, limit: usize) -> Self {
+        self.settings.chat_history_limit = limit;
+        self
+    }
+
+    /// Record inputs of all lobbies so they can be exported and replayed
+    pub fn record_games(mut self, enabled: bool) -> Self {
+        self.settings.record_games = enabled;
+        self
+    }
+
+    /// Ping WebSocket clients every `interval` and disconnect those that
+    /// don't answer within `timeout`
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.settings.heartbeat = Heartbeat { interval, timeout };
+        self
+    }
+
+    /// Matchmaking accepts rating differences up to `initial_range`, widened by
+    /// `widening_per_second` for every second a player has been queued
+    pub fn matchmaking(mut self, initial_range: u32, widening_per_second: u32) -> Self {
+        self.settings.matchmaking = matchmaking::Matchmaking {
+            initial_range,
+            widening_per_second,
+        };
+        self
+    }
+
+    /// Time source for the server, e.g. [`clock::ManualClock`] in simulations
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.settings.clock = std::sync::Arc::new(clock);
+        self
+    }
+
+    /// Start the game server without any HTTP routes, e.g. to connect
+    /// [`testing::TestClient`]s or a custom transport
+    pub fn spawn_server(self) -> (JoinHandle<()>, ServerRemote) {
+        let Self {
+            registry,
+            authenticator,
+            match_store,
+            rating_store,
+            settings,
+        } = self;
+        let rating_store =
+            rating_store.unwrap_or_else(|| Box::new(rating::MemoryRatingStore::new()));
+        game_server::spawn(registry, authenticator, match_store, rating_store, settings)
+    }
+
     pub fn spawn(
         self,
     ) -> (
         JoinHandle<()>,
         impl warp::Filter<Extract = impl Reply, Error = Rejection> + Clone,
     ) {
-        let Self { registry } = self;
-        let (jh, game_server_handle) = game_server::spawn(registry);
+        let (jh, game_server_handle) = self.spawn_server();
 
         let wasm_bg = warp::path("wasm")
             .and(warp::path("wgfw_wasm_bg.wasm"))
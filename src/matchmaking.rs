@@ -0,0 +1,303 @@
+//! Matchmaking queue, grouping players by game mode and rating
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use wgfw_protocol::PlayerId;
+
+/// How often the queue is rematched, as the rating ranges widen
+const ROUND_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Smallest match, whatever the mode allows: nobody is matched alone
+pub(crate) const MIN_MATCH_SIZE: usize = 2;
+
+/// Rating range settings of the matchmaking queue
+#[derive(Debug, Clone, Copy)]
+pub struct Matchmaking {
+    /// Largest rating difference accepted right after queueing
+    pub initial_range: u32,
+    /// Added to the accepted difference for every second in the queue
+    pub widening_per_second: u32,
+}
+impl Default for Matchmaking {
+    fn default() -> Self {
+        Self {
+            initial_range: 100,
+            widening_per_second: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct QueueEntry {
+    pub player: PlayerId,
+    pub mode: String,
+    pub rating: Option<u32>,
+    pub since: Instant,
+}
+
+#[derive(Debug)]
+pub(crate) struct Matchmaker {
+    settings: Matchmaking,
+    /// Oldest first
+    queue: Vec<QueueEntry>,
+    /// Next rematch, while anyone is queued
+    next_round: Option<Instant>,
+    /// Position and queue length last reported to each queued player
+    reported: HashMap<PlayerId, (usize, usize)>,
+}
+
+impl Matchmaker {
+    pub fn new(settings: Matchmaking) -> Self {
+        Self {
+            settings,
+            queue: Vec::new(),
+            next_round: None,
+            reported: HashMap::new(),
+        }
+    }
+
+    pub fn contains(&self, player: PlayerId) -> bool {
+        self.queue.iter().any(|entry| entry.player == player)
+    }
+
+    /// Add to the queue, or put back an entry that couldn't be matched
+    pub fn join(&mut self, entry: QueueEntry) {
+        if self.next_round.is_none() {
+            self.next_round = Some(entry.since + ROUND_INTERVAL);
+        }
+        let index = self
+            .queue
+            .partition_point(|other| other.since <= entry.since);
+        self.queue.insert(index, entry);
+    }
+
+    /// Returns false if not queued
+    pub fn leave(&mut self, player: PlayerId) -> bool {
+        let len = self.queue.len();
+        self.queue.retain(|entry| entry.player != player);
+        if self.queue.is_empty() {
+            self.next_round = None;
+        }
+        self.queue.len() != len
+    }
+
+    /// Queue position (from 1) and number of players queued for the same
+    /// mode, for the players whose values changed since the last call
+    pub fn position_changes(&mut self) -> Vec<(PlayerId, String, usize, usize)> {
+        let mut changes = Vec::new();
+        for entry in &self.queue {
+            let same_mode = self.queue.iter().filter(|other| other.mode == entry.mode);
+            let position = same_mode
+                .clone()
+                .take_while(|other| other.player != entry.player)
+                .count();
+            let current = (position + 1, same_mode.count());
+            if self.reported.insert(entry.player, current) != Some(current) {
+                changes.push((entry.player, entry.mode.clone(), current.0, current.1));
+            }
+        }
+
+        let queue = &self.queue;
+        self.reported
+            .retain(|player, _| queue.iter().any(|entry| entry.player == *player));
+        changes
+    }
+
+    pub fn next_round(&self) -> Option<Instant> {
+        self.next_round
+    }
+
+    /// Accepted rating difference of an entry at `now`
+    fn range(&self, entry: &QueueEntry, now: Instant) -> u32 {
+        let waited = now.saturating_duration_since(entry.since).as_secs();
+        let widening = u32::try_from(waited)
+            .unwrap_or(u32::MAX)
+            .saturating_mul(self.settings.widening_per_second);
+        self.settings.initial_range.saturating_add(widening)
+    }
+
+    /// Unrated players match anyone
+    fn compatible(&self, a: &QueueEntry, b: &QueueEntry, now: Instant) -> bool {
+        match (a.rating, b.rating) {
+            (Some(x), Some(y)) => x.abs_diff(y) <= self.range(a, now).min(self.range(b, now)),
+            _ => true,
+        }
+    }
+
+    /// Group queued players into matches, oldest first. `sizes` gives the
+    /// minimum and maximum number of players of a mode; without a maximum,
+    /// matches have the minimum number. Matches have at least [`MIN_MATCH_SIZE`]
+    /// players. Matched players are removed from the queue.
+    pub fn find_matches(
+        &mut self,
+        now: Instant,
+        sizes: impl Fn(&str) -> (usize, Option<usize>),
+    ) -> Vec<Vec<QueueEntry>> {
+        let mut matched = vec![false; self.queue.len()];
+        let mut groups = Vec::new();
+
+        for anchor in 0..self.queue.len() {
+            if matched[anchor] {
+                continue;
+            }
+
+            let mode = &self.queue[anchor].mode;
+            let (min, max) = sizes(mode);
+            let min = min.max(MIN_MATCH_SIZE);
+            let limit = max.unwrap_or(min).max(min);
+            let mut group = vec![anchor];
+            for (candidate, entry) in self.queue.iter().enumerate().skip(anchor + 1) {
+                if group.len() >= limit {
+                    break;
+                }
+                if matched[candidate] || entry.mode != *mode {
+                    continue;
+                }
+                if group
+                    .iter()
+                    .all(|member| self.compatible(&self.queue[*member], entry, now))
+                {
+                    group.push(candidate);
+                }
+            }
+
+            if group.len() >= min {
+                for index in &group {
+                    matched[*index] = true;
+                }
+                groups.push(group);
+            }
+        }
+
+        let groups = groups
+            .into_iter()
+            .map(|group| {
+                group
+                    .into_iter()
+                    .map(|index| self.queue[index].clone())
+                    .collect()
+            })
+            .collect();
+
+        let mut matched = matched.into_iter();
+        self.queue.retain(|_| !matched.next().unwrap());
+        self.next_round = if self.queue.is_empty() {
+            None
+        } else {
+            Some(now + ROUND_INTERVAL)
+        };
+        groups
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(mode: &str, rating: Option<u32>, since: Instant) -> QueueEntry {
+        QueueEntry {
+            player: PlayerId::new(),
+            mode: mode.to_owned(),
+            rating,
+            since,
+        }
+    }
+
+    fn players(groups: &[Vec<QueueEntry>]) -> Vec<Vec<PlayerId>> {
+        groups
+            .iter()
+            .map(|group| group.iter().map(|entry| entry.player).collect())
+            .collect()
+    }
+
+    fn pairs(_mode: &str) -> (usize, Option<usize>) {
+        (2, None)
+    }
+
+    #[test]
+    fn ranges_widen_while_waiting() {
+        let start = Instant::now();
+        let mut matchmaker = Matchmaker::new(Matchmaking {
+            initial_range: 100,
+            widening_per_second: 10,
+        });
+        let low = entry("duel", Some(1000), start);
+        let high = entry("duel", Some(1250), start);
+        let expected = vec![vec![low.player, high.player]];
+        matchmaker.join(low);
+        matchmaker.join(high);
+
+        let now = start + Duration::from_secs(14);
+        assert!(matchmaker.find_matches(now, pairs).is_empty());
+        assert_eq!(matchmaker.next_round(), Some(now + ROUND_INTERVAL));
+
+        let now = start + Duration::from_secs(15);
+        assert_eq!(players(&matchmaker.find_matches(now, pairs)), expected);
+        assert_eq!(matchmaker.next_round(), None);
+    }
+
+    #[test]
+    fn modes_are_matched_separately() {
+        let start = Instant::now();
+        let mut matchmaker = Matchmaker::new(Matchmaking::default());
+        let first = entry("duel", None, start);
+        let other = entry("race", None, start + Duration::from_secs(1));
+        let second = entry("duel", None, start + Duration::from_secs(2));
+        let expected = vec![vec![first.player, second.player]];
+        let waiting = other.player;
+        matchmaker.join(first);
+        matchmaker.join(other);
+        matchmaker.join(second);
+
+        let matches = matchmaker.find_matches(start + Duration::from_secs(3), pairs);
+        assert_eq!(players(&matches), expected);
+        assert!(matchmaker.contains(waiting));
+    }
+
+    #[test]
+    fn requeued_entries_keep_their_place() {
+        let start = Instant::now();
+        let mut matchmaker = Matchmaker::new(Matchmaking::default());
+        let oldest = entry("duel", None, start);
+        let newer = entry("duel", None, start + Duration::from_secs(1));
+        let newest = entry("duel", None, start + Duration::from_secs(2));
+        let expected = vec![vec![oldest.player, newer.player]];
+        matchmaker.join(newer);
+        matchmaker.join(newest);
+        // Put back after a failed match, with the original queueing time
+        matchmaker.join(oldest);
+
+        let changes = matchmaker.position_changes();
+        let positions: Vec<_> = changes.iter().map(|change| (change.0, change.2)).collect();
+        assert_eq!(positions[0], (expected[0][0], 1));
+        assert!(matchmaker.position_changes().is_empty());
+
+        let matches = matchmaker.find_matches(start + Duration::from_secs(3), |_| (2, Some(2)));
+        assert_eq!(players(&matches), expected);
+        assert_eq!(matchmaker.position_changes().len(), 1);
+    }
+
+    #[test]
+    fn nobody_is_matched_alone() {
+        let start = Instant::now();
+        let mut matchmaker = Matchmaker::new(Matchmaking::default());
+        let first = entry("solo", None, start);
+        let second = entry("solo", None, start + Duration::from_secs(1));
+        let expected = vec![vec![first.player, second.player]];
+        let waiting = first.player;
+        matchmaker.join(first);
+
+        let now = start + Duration::from_secs(2);
+        assert!(matchmaker.find_matches(now, |_| (1, None)).is_empty());
+        assert!(matchmaker.contains(waiting));
+
+        matchmaker.join(second);
+        assert_eq!(
+            players(&matchmaker.find_matches(now, |_| (1, None))),
+            expected
+        );
+    }
+}
@@ -0,0 +1,399 @@
+//! Skill ratings per player and game mode
+//!
+//! Enable ratings for a game mode with [`GameModeInfo::rating`]. When a game
+//! of that mode reports a result, the ratings of the placed players are
+//! updated and saved to the [`RatingStore`]. Ratings of the lobby members are
+//! available in [`GameCommon::ratings`], and matchmaking uses them instead of
+//! client-provided ratings.
+//!
+//! [`GameModeInfo::rating`]: crate::GameModeInfo::rating
+//! [`GameCommon::ratings`]: crate::game_state::GameCommon::ratings
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use wgfw_protocol::{GameResult, PlayerId, Rating};
+
+use crate::history::StoreResult;
+
+/// Glicko-2 scale factor between the rating and its internal scale
+const GLICKO2_SCALE: f64 = 173.7178;
+/// Convergence tolerance of the Glicko-2 volatility iteration
+const GLICKO2_EPSILON: f64 = 0.000_001;
+
+/// How ratings are updated, see [`RatingSystem::elo`] and [`RatingSystem::glicko2`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RatingSystem(System);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum System {
+    Elo { k_factor: f64 },
+    Glicko2 { tau: f64 },
+}
+
+impl RatingSystem {
+    /// Pairwise Elo. Each game moves a rating by at most `k_factor`.
+    pub fn elo(k_factor: f64) -> Result<Self, InvalidParameter> {
+        positive(k_factor)?;
+        Ok(Self(System::Elo { k_factor }))
+    }
+
+    /// Glicko-2, treating each game as a rating period. `tau` limits how
+    /// fast the volatility changes, typically between 0.3 and 1.2.
+    pub fn glicko2(tau: f64) -> Result<Self, InvalidParameter> {
+        positive(tau)?;
+        Ok(Self(System::Glicko2 { tau }))
+    }
+}
+
+/// A rating system parameter is not a positive number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidParameter;
+impl fmt::Display for InvalidParameter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Rating system parameter must be positive")
+    }
+}
+impl std::error::Error for InvalidParameter {}
+
+fn positive(value: f64) -> Result<(), InvalidParameter> {
+    if value > 0.0 && value.is_finite() {
+        Ok(())
+    } else {
+        Err(InvalidParameter)
+    }
+}
+
+/// Persistent ratings, loaded as players join rated lobbies and saved after
+/// each rated game. The server waits for these calls, so they must not block.
+pub trait RatingStore: Send + Sync {
+    fn load(&self, player: PlayerId, mode: &str) -> StoreResult<Option<Rating>>;
+    fn save(&self, player: PlayerId, mode: &str, rating: &Rating) -> StoreResult<()>;
+}
+
+/// Keeps ratings until the server stops
+#[derive(Default)]
+pub struct MemoryRatingStore {
+    ratings: Mutex<HashMap<(PlayerId, String), Rating>>,
+}
+impl MemoryRatingStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl RatingStore for MemoryRatingStore {
+    fn load(&self, player: PlayerId, mode: &str) -> StoreResult<Option<Rating>> {
+        let ratings = self.ratings.lock().unwrap();
+        Ok(ratings.get(&(player, mode.to_owned())).copied())
+    }
+
+    fn save(&self, player: PlayerId, mode: &str, rating: &Rating) -> StoreResult<()> {
+        let mut ratings = self.ratings.lock().unwrap();
+        ratings.insert((player, mode.to_owned()), *rating);
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct RatingLine {
+    player: PlayerId,
+    mode: String,
+    rating: Rating,
+}
+
+/// Appends updated ratings to a file, one JSON object per line. The last
+/// line of a player and mode wins. The file is read once, on the first load,
+/// and kept in memory after that.
+pub struct FileRatingStore {
+    path: PathBuf,
+    /// Latest ratings, once the file has been read. Also serializes appends.
+    ratings: Mutex<Option<HashMap<(PlayerId, String), Rating>>>,
+}
+impl FileRatingStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            ratings: Mutex::new(None),
+        }
+    }
+
+    fn read(&self) -> StoreResult<HashMap<(PlayerId, String), Rating>> {
+        let mut ratings = HashMap::new();
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(ratings),
+            Err(error) => return Err(error.into()),
+        };
+
+        for line in BufReader::new(file).lines() {
+            let line: RatingLine = match serde_json::from_str(&line?) {
+                Ok(line) => line,
+                Err(error) => {
+                    log::warn!("Skipping invalid line in {:?}: {}", self.path, error);
+                    continue;
+                }
+            };
+            ratings.insert((line.player, line.mode), line.rating);
+        }
+        Ok(ratings)
+    }
+}
+
+impl RatingStore for FileRatingStore {
+    fn load(&self, player: PlayerId, mode: &str) -> StoreResult<Option<Rating>> {
+        let mut ratings = self.ratings.lock().unwrap();
+        let ratings = match &mut *ratings {
+            Some(ratings) => ratings,
+            None => ratings.insert(self.read()?),
+        };
+        Ok(ratings.get(&(player, mode.to_owned())).copied())
+    }
+
+    fn save(&self, player: PlayerId, mode: &str, rating: &Rating) -> StoreResult<()> {
+        let mut ratings = self.ratings.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut line = serde_json::to_vec(&RatingLine {
+            player,
+            mode: mode.to_owned(),
+            rating: *rating,
+        })?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        if let Some(ratings) = &mut *ratings {
+            ratings.insert((player, mode.to_owned()), *rating);
+        }
+        Ok(())
+    }
+}
+
+/// New ratings of the players placed in `result`, from their current
+/// `ratings`. Players without a current rating are not rated. Without
+/// placements, the winners are placed above everyone else. Results with
+/// neither are not rated.
+pub fn update_ratings(
+    system: RatingSystem,
+    ratings: &HashMap<PlayerId, Rating>,
+    result: &GameResult,
+) -> HashMap<PlayerId, Rating> {
+    if result.placements.is_empty() && result.winners.is_empty() {
+        return HashMap::new();
+    }
+    let placements = if result.placements.is_empty() {
+        let mut others: Vec<PlayerId> = ratings
+            .keys()
+            .filter(|player| !result.winners.contains(player))
+            .copied()
+            .collect();
+        others.sort();
+        vec![result.winners.clone(), others]
+    } else {
+        result.placements.clone()
+    };
+
+    // Placement of each rated player, lower is better
+    let places: Vec<(PlayerId, usize, Rating)> = placements
+        .iter()
+        .enumerate()
+        .flat_map(|(place, group)| group.iter().map(move |player| (*player, place)))
+        .filter_map(|(player, place)| Some((player, place, *ratings.get(&player)?)))
+        .collect();
+
+    let mut updated = HashMap::new();
+    if places.len() < 2 {
+        return updated;
+    }
+
+    for (player, place, rating) in &places {
+        // Opponent ratings and scores from the point of view of `player`
+        let games: Vec<(Rating, f64)> = places
+            .iter()
+            .filter(|(other, _, _)| other != player)
+            .map(|(_, other_place, other_rating)| {
+                let score = match place.cmp(other_place) {
+                    Ordering::Less => 1.0,
+                    Ordering::Equal => 0.5,
+                    Ordering::Greater => 0.0,
+                };
+                (*other_rating, score)
+            })
+            .collect();
+
+        let mut new = match system.0 {
+            System::Elo { k_factor } => elo(*rating, &games, k_factor),
+            System::Glicko2 { tau } => glicko2(*rating, &games, tau),
+        };
+        new.games += 1;
+        updated.insert(*player, new);
+    }
+    updated
+}
+
+/// Pairwise Elo, with the K-factor split between the opponents
+fn elo(rating: Rating, games: &[(Rating, f64)], k_factor: f64) -> Rating {
+    let k = k_factor / games.len() as f64;
+    let change: f64 = games
+        .iter()
+        .map(|(opponent, score)| {
+            let expected = 1.0 / (1.0 + 10f64.powf((opponent.rating - rating.rating) / 400.0));
+            k * (score - expected)
+        })
+        .sum();
+    Rating {
+        rating: rating.rating + change,
+        ..rating
+    }
+}
+
+/// One Glicko-2 rating period, as in Glickman's "Example of the Glicko-2 system"
+fn glicko2(rating: Rating, games: &[(Rating, f64)], tau: f64) -> Rating {
+    let mu = (rating.rating - Rating::INITIAL) / GLICKO2_SCALE;
+    let phi = rating.deviation / GLICKO2_SCALE;
+    let sigma = rating.volatility;
+
+    let g = |phi: f64| 1.0 / (1.0 + 3.0 * phi * phi / (PI * PI)).sqrt();
+    let terms: Vec<(f64, f64, f64)> = games
+        .iter()
+        .map(|(opponent, score)| {
+            let mu_j = (opponent.rating - Rating::INITIAL) / GLICKO2_SCALE;
+            let g_j = g(opponent.deviation / GLICKO2_SCALE);
+            let expected = 1.0 / (1.0 + (-g_j * (mu - mu_j)).exp());
+            (g_j, expected, *score)
+        })
+        .collect();
+
+    let v = 1.0
+        / terms
+            .iter()
+            .map(|(g_j, e, _)| g_j * g_j * e * (1.0 - e))
+            .sum::<f64>();
+    let improvement: f64 = terms.iter().map(|(g_j, e, s)| g_j * (s - e)).sum();
+    let delta = v * improvement;
+
+    // New volatility by the Illinois algorithm
+    let a = (sigma * sigma).ln();
+    let f = |x: f64| {
+        let ex = x.exp();
+        let d = phi * phi + v + ex;
+        ex * (delta * delta - phi * phi - v - ex) / (2.0 * d * d) - (x - a) / (tau * tau)
+    };
+    let mut lower = a;
+    let mut upper = if delta * delta > phi * phi + v {
+        (delta * delta - phi * phi - v).ln()
+    } else {
+        let mut k = 1.0;
+        while f(a - k * tau) < 0.0 {
+            k += 1.0;
+        }
+        a - k * tau
+    };
+    let mut f_lower = f(lower);
+    let mut f_upper = f(upper);
+    while (upper - lower).abs() > GLICKO2_EPSILON {
+        let c = lower + (lower - upper) * f_lower / (f_upper - f_lower);
+        let f_c = f(c);
+        if f_c * f_upper <= 0.0 {
+            lower = upper;
+            f_lower = f_upper;
+        } else {
+            f_lower /= 2.0;
+        }
+        upper = c;
+        f_upper = f_c;
+    }
+    let sigma = (lower / 2.0).exp();
+
+    let phi_star = (phi * phi + sigma * sigma).sqrt();
+    let phi = 1.0 / (1.0 / (phi_star * phi_star) + 1.0 / v).sqrt();
+    let mu = mu + phi * phi * improvement;
+
+    Rating {
+        rating: GLICKO2_SCALE * mu + Rating::INITIAL,
+        deviation: GLICKO2_SCALE * phi,
+        volatility: sigma,
+        games: rating.games,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rating(rating: f64, deviation: f64) -> Rating {
+        Rating {
+            rating,
+            deviation,
+            ..Rating::default()
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        assert_eq!(RatingSystem::elo(0.0), Err(InvalidParameter));
+        assert_eq!(RatingSystem::elo(f64::NAN), Err(InvalidParameter));
+        assert_eq!(RatingSystem::glicko2(-0.5), Err(InvalidParameter));
+        assert!(RatingSystem::glicko2(0.5).is_ok());
+    }
+
+    #[test]
+    fn glicko2_matches_glickman_example() {
+        let games = [
+            (rating(1400.0, 30.0), 1.0),
+            (rating(1550.0, 100.0), 0.0),
+            (rating(1700.0, 300.0), 0.0),
+        ];
+        let new = glicko2(rating(1500.0, 200.0), &games, 0.5);
+        assert!((new.rating - 1464.05).abs() < 0.01, "{}", new.rating);
+        assert!((new.deviation - 151.52).abs() < 0.01, "{}", new.deviation);
+        assert!(
+            (new.volatility - 0.05999).abs() < 0.00001,
+            "{}",
+            new.volatility
+        );
+    }
+
+    #[test]
+    fn elo_is_zero_sum() {
+        let winner = PlayerId::new();
+        let loser = PlayerId::new();
+        let ratings = HashMap::from([
+            (winner, rating(1400.0, 350.0)),
+            (loser, rating(1600.0, 350.0)),
+        ]);
+        let result = GameResult {
+            winners: vec![winner],
+            ..GameResult::default()
+        };
+
+        let updated = update_ratings(RatingSystem::elo(32.0).unwrap(), &ratings, &result);
+        let gain = updated[&winner].rating - 1400.0;
+        let loss = updated[&loser].rating - 1600.0;
+        assert!(gain > 0.0 && gain < 32.0);
+        assert!((gain + loss).abs() < 1e-9);
+        assert_eq!(updated[&winner].games, 1);
+        assert_eq!(updated[&loser].games, 1);
+    }
+
+    #[test]
+    fn results_without_an_outcome_are_not_rated() {
+        let ratings = HashMap::from([
+            (PlayerId::new(), rating(1500.0, 350.0)),
+            (PlayerId::new(), rating(1500.0, 350.0)),
+        ]);
+        let result = GameResult::default();
+
+        let updated = update_ratings(RatingSystem::elo(32.0).unwrap(), &ratings, &result);
+        assert!(updated.is_empty());
+    }
+}
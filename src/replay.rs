@@ -0,0 +1,349 @@
+//! Input recording and deterministic replay
+//!
+//! Enable recording with [`Builder::record_games`](crate::Builder::record_games).
+//! Games should take randomness only from
+//! [`GameCommon::rng`](crate::game_state::GameCommon::rng), and time only
+//! from [`GameCommon::clock`](crate::game_state::GameCommon::clock).
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use wgfw_protocol::{LobbyPhase, PlayerId, Profile, Rating};
+
+use crate::auth::Claims;
+use crate::clock::{Clock, ManualClock};
+use crate::game_registry::GameRegistry;
+use crate::game_state::{EventId, GameCommon, Lobby, Updates};
+use crate::latency::{ConnectionQuality, PlayerConnection};
+use crate::rng::{LobbyRng, Seed};
+use crate::teams::Teams;
+
+/// Something that was fed to the game
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum Input {
+    /// A member joined, with what the game can see of them
+    Join {
+        player: PlayerId,
+        bot: bool,
+        claims: Option<Claims>,
+        profile: Option<Profile>,
+        rating: Option<Rating>,
+    },
+    /// A member left, `leader` leads the lobby afterwards
+    Leave {
+        player: PlayerId,
+        leader: PlayerId,
+    },
+    /// A member was kicked, `leader` leads the lobby afterwards
+    Kick {
+        player: PlayerId,
+        leader: PlayerId,
+    },
+    Disconnect(PlayerId),
+    Reconnect(PlayerId),
+    /// Started a game with this game seed
+    Start(Seed),
+    Message {
+        player: PlayerId,
+        message: serde_json::Value,
+    },
+    Event {
+        id: EventId,
+        payload: serde_json::Value,
+    },
+    /// Number of ticks run back to back
+    Ticks(u32),
+    /// Switched to another game mode
+    ChangeMode(String),
+    ConnectionQuality(PlayerId, ConnectionQuality),
+    /// Switched teams, or left all teams with `None`
+    Team(PlayerId, Option<String>),
+    /// Made the leader
+    Promote(PlayerId),
+    /// Changed profile
+    Profile(PlayerId, Profile),
+    /// Ratings of the members were reloaded
+    Ratings(Vec<(PlayerId, Rating)>),
+    /// Started a ready check
+    ReadyCheck,
+    /// Confirmed or withdrew readiness
+    Ready(PlayerId, bool),
+    /// The ready check was called off for lack of players
+    CancelReadyCheck,
+}
+
+/// Number of entries kept by a [`Recorder`]
+pub const MAX_LOG_ENTRIES: usize = 100_000;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LogEntry {
+    /// Time since the recording started
+    pub at: Duration,
+    pub input: Input,
+    /// Events scheduled while handling the input, used to map event ids on replay
+    pub scheduled: Vec<EventId>,
+    /// Hash of the public state after the input
+    pub state_hash: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GameLog {
+    pub mode: String,
+    /// Game seed when the recording started, see [`LobbyRng::game_seed`].
+    /// The lobby seed isn't recorded, so later game seeds stay secret.
+    pub game_seed: Seed,
+    /// Leader when the recording started
+    pub leader: PlayerId,
+    /// The most recent entries, at most [`MAX_LOG_ENTRIES`]
+    pub entries: VecDeque<LogEntry>,
+    /// Number of older entries dropped to stay within [`MAX_LOG_ENTRIES`].
+    /// Logs with dropped entries can't be replayed.
+    #[serde(default)]
+    pub dropped: usize,
+    /// The recording started after others had joined or a game had started.
+    /// The game state at the start is unknown, so such logs can't be replayed.
+    #[serde(default)]
+    pub mid_lobby: bool,
+}
+
+/// Records inputs of a lobby
+#[derive(Debug)]
+pub struct Recorder {
+    started: Instant,
+    log: GameLog,
+}
+impl Recorder {
+    pub(crate) fn new(common: &GameCommon, mode: &str) -> Self {
+        Self {
+            started: common.clock.now(),
+            log: GameLog {
+                mode: mode.to_owned(),
+                game_seed: common.rng.game_seed(),
+                leader: common.leader,
+                entries: VecDeque::new(),
+                dropped: 0,
+                mid_lobby: common.players.len() > 1
+                    || common.phase != LobbyPhase::Waiting
+                    || common.rng.games() > 0,
+            },
+        }
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        now: Instant,
+        input: Input,
+        updates: &Updates,
+        state: &serde_json::Value,
+    ) {
+        if self.log.entries.len() >= MAX_LOG_ENTRIES {
+            self.log.entries.pop_front();
+            self.log.dropped += 1;
+        }
+        self.log.entries.push_back(LogEntry {
+            at: now - self.started,
+            input,
+            scheduled: updates.events.iter().map(|event| event.id).collect(),
+            state_hash: state_hash(state),
+        });
+    }
+
+    pub fn log(&self) -> &GameLog {
+        &self.log
+    }
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    /// The game mode is not in the registry
+    UnknownMode(String),
+    /// A logged event was never scheduled during the replay
+    UnknownEvent { entry: usize },
+    /// The replay reached a different state after this entry
+    Diverged { entry: usize },
+    /// The log doesn't start from the beginning of the recording
+    Truncated,
+    /// The recording started in a lobby that was already in use
+    MidLobby,
+}
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownMode(mode) => write!(f, "Unknown game mode {:?}", mode),
+            Self::UnknownEvent { entry } => write!(f, "Unknown event at entry {}", entry),
+            Self::Diverged { entry } => write!(f, "Replay diverged at entry {}", entry),
+            Self::Truncated => write!(f, "Log is missing its oldest entries"),
+            Self::MidLobby => write!(f, "Recording started after the lobby was in use"),
+        }
+    }
+}
+impl std::error::Error for ReplayError {}
+
+/// Feed a log into a fresh game from the registry, checking that each input
+/// leads to the recorded state. Returns the final lobby. Connection states
+/// follow the recorded joins, disconnects and reconnects, but round-trip
+/// times aren't recorded. Bots are replayed through their recorded messages.
+pub fn replay(registry: &GameRegistry, log: &GameLog) -> Result<Lobby, ReplayError> {
+    if log.dropped > 0 {
+        return Err(ReplayError::Truncated);
+    }
+    if log.mid_lobby {
+        return Err(ReplayError::MidLobby);
+    }
+    let mode = registry
+        .games
+        .get(&log.mode)
+        .ok_or_else(|| ReplayError::UnknownMode(log.mode.clone()))?;
+
+    let clock = ManualClock::new();
+    let started = clock.now();
+    let mut lobby = Lobby {
+        common: GameCommon {
+            clock: Arc::new(clock.clone()),
+            rng: LobbyRng::from_game_seed(log.game_seed),
+            teams: Teams::new(mode.info.teams.clone()),
+            ..GameCommon::new(log.leader)
+        },
+        state: (mode.constructor)(),
+        mode: log.mode.clone(),
+        mode_info: mode.info.clone(),
+        recording: None,
+    };
+    lobby.set_tick_rate(mode.info.tick_rate.unwrap_or(0));
+
+    // Recorded event id to the id scheduled during replay
+    let mut event_ids: HashMap<EventId, EventId> = HashMap::new();
+
+    for (index, entry) in log.entries.iter().enumerate() {
+        clock.advance_to(started + entry.at);
+
+        let updates = match &entry.input {
+            Input::Join {
+                player,
+                bot,
+                claims,
+                profile,
+                rating,
+            } => {
+                let common = &mut lobby.common;
+                common.players.insert(*player);
+                common.connections.insert(*player, PlayerConnection::new());
+                if *bot {
+                    common.bots.insert(*player);
+                }
+                if let Some(claims) = claims {
+                    common.claims.insert(*player, claims.clone());
+                }
+                if let Some(profile) = profile {
+                    common.profiles.insert(*player, profile.clone());
+                }
+                if let Some(rating) = rating {
+                    common.ratings.insert(*player, *rating);
+                }
+                lobby.on_join(*player)
+            }
+            Input::Leave { player, leader } => {
+                lobby.common.remove_player(player);
+                lobby.common.leader = *leader;
+                lobby.on_leave(*player)
+            }
+            Input::Kick { player, leader } => {
+                lobby.common.remove_player(player);
+                lobby.common.leader = *leader;
+                lobby.on_kick(*player)
+            }
+            Input::Disconnect(player) => lobby.disconnect(*player),
+            Input::Reconnect(player) => {
+                lobby
+                    .common
+                    .connections
+                    .insert(*player, PlayerConnection::new());
+                lobby.on_reconnect(*player)
+            }
+            Input::Start(seed) => lobby.begin_with(Some(*seed)),
+            Input::Message { player, message } => lobby.on_message_from(*player, message.clone()).0,
+            Input::Event { id, payload } => {
+                let id = *event_ids
+                    .get(id)
+                    .ok_or(ReplayError::UnknownEvent { entry: index })?;
+                lobby.on_event(id, payload.clone())
+            }
+            Input::Ticks(count) => lobby.run_tick_count(*count),
+            Input::Team(player, team) => lobby
+                .set_team(*player, team.clone())
+                .map_err(|_| ReplayError::Diverged { entry: index })?,
+            Input::Promote(player) => lobby
+                .promote(*player)
+                .map_err(|_| ReplayError::Diverged { entry: index })?,
+            Input::Profile(player, profile) => lobby.set_profile(*player, profile.clone()),
+            Input::Ratings(ratings) => lobby.set_ratings(ratings.iter().copied().collect()),
+            Input::ReadyCheck => {
+                let updates = lobby
+                    .start()
+                    .map_err(|_| ReplayError::Diverged { entry: index })?;
+                if lobby.common.phase != LobbyPhase::ReadyCheck {
+                    return Err(ReplayError::Diverged { entry: index });
+                }
+                updates
+            }
+            Input::Ready(player, ready) => {
+                if *ready {
+                    lobby.common.ready.insert(*player);
+                } else {
+                    lobby.common.ready.remove(player);
+                }
+                Updates::NONE
+            }
+            Input::CancelReadyCheck => {
+                lobby.cancel_ready_check();
+                Updates::CHANGED
+            }
+            Input::ConnectionQuality(player, quality) => {
+                lobby.on_connection_quality(*player, *quality)
+            }
+            Input::ChangeMode(name) => {
+                let mode = registry
+                    .games
+                    .get(name)
+                    .ok_or_else(|| ReplayError::UnknownMode(name.clone()))?;
+                let updates = lobby
+                    .change_mode(name.clone(), (mode.constructor)(), mode.info.clone())
+                    .map_err(|_| ReplayError::Diverged { entry: index })?;
+                lobby.set_tick_rate(mode.info.tick_rate.unwrap_or(0));
+                updates
+            }
+        };
+
+        if updates.events.len() != entry.scheduled.len() {
+            return Err(ReplayError::Diverged { entry: index });
+        }
+        for (recorded, event) in entry.scheduled.iter().zip(&updates.events) {
+            event_ids.insert(*recorded, event.id);
+        }
+
+        if let Some(hz) = updates.tick_rate {
+            lobby.set_tick_rate(hz);
+        }
+
+        if state_hash(&lobby.public_state()) != entry.state_hash {
+            return Err(ReplayError::Diverged { entry: index });
+        }
+    }
+
+    Ok(lobby)
+}
+
+/// FNV-1a of the serialized state, stable across builds
+fn state_hash(state: &serde_json::Value) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in state.to_string().bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
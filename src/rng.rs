@@ -0,0 +1,138 @@
+//! Seeded random numbers for games
+//!
+//! Each lobby has a [`LobbyRng`] in [`GameCommon::rng`](crate::game_state::GameCommon::rng).
+//! Every started game reseeds it with a seed derived from the lobby seed, so
+//! recordings replay deterministically and revealing the seed of one game
+//! doesn't reveal the seeds of later games. The lobby seed itself never
+//! leaves the server; recordings hold the game seeds instead.
+
+use std::sync::Mutex;
+
+use orion::hazardous::hash::sha2::sha256::Sha256;
+use rand_chacha::ChaCha20Rng;
+use rand_core::{OsRng, RngCore, SeedableRng};
+
+pub type Seed = [u8; 32];
+
+#[derive(Debug)]
+pub struct LobbyRng {
+    /// Seed of the lobby, secret
+    seed: Seed,
+    /// Seed of the current game
+    game_seed: Seed,
+    /// Number of games started
+    games: u64,
+    rng: Mutex<ChaCha20Rng>,
+}
+
+impl Default for LobbyRng {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LobbyRng {
+    /// Randomly seeded
+    pub fn new() -> Self {
+        let mut seed = Seed::default();
+        OsRng.fill_bytes(&mut seed);
+        Self::from_seed(seed)
+    }
+
+    /// Fixed seed, e.g. for tests
+    pub fn from_seed(seed: Seed) -> Self {
+        let game_seed = derive(&seed, 0);
+        Self {
+            seed,
+            game_seed,
+            games: 0,
+            rng: Mutex::new(ChaCha20Rng::from_seed(game_seed)),
+        }
+    }
+
+    /// Continue from a known game seed, e.g. in replays. Later games must be
+    /// started with [`LobbyRng::start_game_with`].
+    pub(crate) fn from_game_seed(game_seed: Seed) -> Self {
+        let mut rng = Self::new();
+        rng.start_game_with(game_seed);
+        rng
+    }
+
+    /// Seed of the current game, safe to reveal once it has finished
+    pub fn game_seed(&self) -> Seed {
+        self.game_seed
+    }
+
+    /// Number of games started
+    pub(crate) fn games(&self) -> u64 {
+        self.games
+    }
+
+    /// Reseed for a new game
+    pub(crate) fn start_game(&mut self) {
+        self.start_game_with(derive(&self.seed, self.games + 1));
+    }
+
+    /// Reseed for a new game with a recorded game seed
+    pub(crate) fn start_game_with(&mut self, game_seed: Seed) {
+        self.games += 1;
+        self.game_seed = game_seed;
+        *self.rng.get_mut().unwrap() = ChaCha20Rng::from_seed(game_seed);
+    }
+
+    pub fn next_u32(&self) -> u32 {
+        self.rng.lock().unwrap().next_u32()
+    }
+
+    pub fn next_u64(&self) -> u64 {
+        self.rng.lock().unwrap().next_u64()
+    }
+
+    pub fn fill_bytes(&self, dest: &mut [u8]) {
+        self.rng.lock().unwrap().fill_bytes(dest)
+    }
+
+    /// Uniformly distributed number in `0..n`. Panics if `n` is zero.
+    pub fn below(&self, n: u64) -> u64 {
+        assert!(n > 0, "Empty range");
+        // Reject the incomplete last zone to avoid modulo bias
+        let zone = u64::MAX - (u64::MAX - n + 1) % n;
+        loop {
+            let value = self.next_u64();
+            if value <= zone {
+                return value % n;
+            }
+        }
+    }
+
+    /// Fisher-Yates shuffle
+    pub fn shuffle<T>(&self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = self.below(i as u64 + 1) as usize;
+            items.swap(i, j);
+        }
+    }
+
+    /// Direct access to the generator, e.g. for use with the `rand` crate
+    pub fn with<R>(&self, f: impl FnOnce(&mut ChaCha20Rng) -> R) -> R {
+        f(&mut self.rng.lock().unwrap())
+    }
+}
+
+/// Seed of game `n` of a lobby, with game 0 before the first start
+fn derive(seed: &Seed, n: u64) -> Seed {
+    let mut input = seed.to_vec();
+    input.extend_from_slice(&n.to_le_bytes());
+    sha256(&input)
+}
+
+pub(crate) fn sha256(data: &[u8]) -> Seed {
+    let digest = Sha256::digest(data).expect("SHA-256 failed");
+    let mut hash = Seed::default();
+    hash.copy_from_slice(digest.as_ref());
+    hash
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,174 @@
+//! Teams of lobby members
+//!
+//! Teams are configured per game mode with [`GameModeInfo::teams`], and the
+//! membership is kept in [`GameCommon::teams`]. New members are put to the
+//! smallest team with room. Players can switch teams, and the leader can move
+//! them, while the game isn't running.
+//!
+//! [`GameModeInfo::teams`]: crate::GameModeInfo::teams
+//! [`GameCommon::teams`]: crate::game_state::GameCommon::teams
+
+use std::collections::HashMap;
+
+use wgfw_protocol::{ErrorReply, PlayerId, TeamInfo};
+
+#[derive(Debug, Clone, Default)]
+pub struct Teams {
+    teams: Vec<TeamInfo>,
+    members: HashMap<PlayerId, String>,
+}
+
+impl Teams {
+    /// Empty teams
+    pub fn new(teams: Vec<TeamInfo>) -> Self {
+        Self {
+            teams,
+            members: HashMap::new(),
+        }
+    }
+
+    /// Configured teams, in order
+    pub fn teams(&self) -> &[TeamInfo] {
+        &self.teams
+    }
+
+    /// Are there any teams configured?
+    pub fn is_enabled(&self) -> bool {
+        !self.teams.is_empty()
+    }
+
+    pub fn team_of(&self, player: PlayerId) -> Option<&str> {
+        self.members.get(&player).map(String::as_str)
+    }
+
+    /// Members of a team, sorted by id
+    pub fn members(&self, team: &str) -> Vec<PlayerId> {
+        let mut members: Vec<PlayerId> = self
+            .members
+            .iter()
+            .filter(|(_, name)| *name == team)
+            .map(|(player, _)| *player)
+            .collect();
+        members.sort();
+        members
+    }
+
+    fn size(&self, team: &str) -> usize {
+        self.members.values().filter(|name| *name == team).count()
+    }
+
+    /// Move a player to a team
+    pub(crate) fn assign(&mut self, player: PlayerId, team: &str) -> Result<(), ErrorReply> {
+        let info = self
+            .teams
+            .iter()
+            .find(|info| info.name == team)
+            .ok_or(ErrorReply::NoSuchTeam)?;
+
+        if self.team_of(player) == Some(team) {
+            return Ok(());
+        }
+        if info.capacity.is_some_and(|max| self.size(team) >= max) {
+            return Err(ErrorReply::TeamFull);
+        }
+
+        self.members.insert(player, team.to_owned());
+        Ok(())
+    }
+
+    /// Take a player out of their team. Returns false if not in any team.
+    pub(crate) fn remove(&mut self, player: PlayerId) -> bool {
+        self.members.remove(&player).is_some()
+    }
+
+    /// Put a player to the smallest team with room, the first one on ties
+    pub(crate) fn auto_assign(&mut self, player: PlayerId) {
+        if self.members.contains_key(&player) {
+            return;
+        }
+
+        let team = self
+            .teams
+            .iter()
+            .map(|info| (info, self.size(&info.name)))
+            .filter(|(info, size)| info.capacity.is_none_or(|max| *size < max))
+            .min_by_key(|(_, size)| *size)
+            .map(|(info, _)| info.name.clone());
+
+        if let Some(team) = team {
+            self.members.insert(player, team);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn teams(capacities: &[(&str, Option<usize>)]) -> Teams {
+        Teams::new(
+            capacities
+                .iter()
+                .map(|(name, capacity)| TeamInfo::new(name, *capacity))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn auto_assign_balances_the_teams() {
+        let mut teams = teams(&[("red", None), ("blue", None)]);
+        let players: Vec<PlayerId> = (0..3).map(|_| PlayerId::new()).collect();
+        for player in &players {
+            teams.auto_assign(*player);
+        }
+        assert_eq!(teams.team_of(players[0]), Some("red"));
+        assert_eq!(teams.team_of(players[1]), Some("blue"));
+        assert_eq!(teams.team_of(players[2]), Some("red"));
+
+        teams.auto_assign(players[2]);
+        assert_eq!(teams.team_of(players[2]), Some("red"));
+        assert!(teams.remove(players[0]));
+        assert!(!teams.remove(players[0]));
+        let late = PlayerId::new();
+        teams.auto_assign(late);
+        assert_eq!(teams.team_of(late), Some("red"));
+    }
+
+    #[test]
+    fn full_teams_are_skipped_and_refused() {
+        let mut teams = teams(&[("solo", Some(1)), ("duo", Some(2))]);
+        let players: Vec<PlayerId> = (0..4).map(|_| PlayerId::new()).collect();
+        for player in &players {
+            teams.auto_assign(*player);
+        }
+        assert_eq!(teams.members("solo"), vec![players[0]]);
+        assert_eq!(teams.members("duo").len(), 2);
+        assert_eq!(teams.team_of(players[3]), None);
+
+        assert!(matches!(
+            teams.assign(players[3], "solo"),
+            Err(ErrorReply::TeamFull)
+        ));
+        assert!(teams.assign(players[0], "solo").is_ok());
+        assert!(matches!(
+            teams.assign(players[3], "trio"),
+            Err(ErrorReply::NoSuchTeam)
+        ));
+
+        assert!(teams.remove(players[0]));
+        assert!(teams.assign(players[3], "solo").is_ok());
+        assert_eq!(teams.members("solo"), vec![players[3]]);
+    }
+
+    #[test]
+    fn members_are_sorted() {
+        let mut teams = teams(&[("red", None)]);
+        let mut players: Vec<PlayerId> = (0..5).map(|_| PlayerId::new()).collect();
+        for player in &players {
+            teams.assign(*player, "red").unwrap();
+        }
+        players.sort();
+        assert_eq!(teams.members("red"), players);
+        assert!(teams.members("blue").is_empty());
+    }
+}
@@ -0,0 +1,581 @@
+//! Test harness for [`Game`] implementations
+//!
+//! [`GameHarness`] runs a single lobby without a server or any connections.
+//! Time only moves when advanced, so scheduled events and ticks fire
+//! deterministically.
+//!
+//! [`TestClient`] talks to a full server started with
+//! [`Builder::spawn_server`](crate::Builder::spawn_server) over in-memory channels.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::{sink, stream};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use wgfw_protocol::{
+    ChatLine, ClientMessage, ClientMessageData, ErrorReply, GameId, GameInfo, GameResult, PlayerId,
+    Profile, ReplyMessage, ServerMessage, ServerSentMessage,
+};
+
+use crate::bots::{Bot, BotPlayer, BotView};
+use crate::clock::{Clock, ManualClock};
+use crate::event_queue::EventQueue;
+use crate::game_registry::GameModeInfo;
+use crate::game_server::{PublishGameState, ServerRemote};
+use crate::game_state::{EventId, Game, GameCommon, Lobby, ScheduledEvent, Updates};
+use crate::latency::PlayerConnection;
+use crate::replay::GameLog;
+use crate::rng::{LobbyRng, Seed};
+use crate::teams::Teams;
+
+/// A single lobby driven directly by the test
+pub struct GameHarness {
+    game_id: GameId,
+    lobby: Lobby,
+    clock: ManualClock,
+    scheduled: EventQueue<(GameId, EventId), ScheduledEvent>,
+    bots: HashMap<PlayerId, BotPlayer>,
+    /// Pending bot turns
+    bot_turns: EventQueue<EventId, PlayerId>,
+    disconnected: HashSet<PlayerId>,
+    /// Players that would have been sent the game state
+    broadcasts: HashSet<PlayerId>,
+    results: Vec<GameResult>,
+    /// Recipients and payloads of notifications
+    notifications: Vec<(Vec<PlayerId>, serde_json::Value)>,
+    /// Lines posted to the lobby chat
+    chat: Vec<ChatLine>,
+}
+
+impl GameHarness {
+    /// Create a lobby with a leader already joined
+    pub fn new<G: Game + 'static>(game: G) -> Self {
+        Self::with_info(game, GameModeInfo::default())
+    }
+
+    pub fn with_info<G: Game + 'static>(game: G, info: GameModeInfo) -> Self {
+        let clock = ManualClock::new();
+        let clock_ref: Arc<dyn Clock> = Arc::new(clock.clone());
+        let leader = PlayerId::new();
+        let tick_rate = info.tick_rate;
+
+        let mut harness = Self {
+            game_id: GameId::new(),
+            lobby: Lobby {
+                common: GameCommon {
+                    clock: clock_ref.clone(),
+                    teams: Teams::new(info.teams.clone()),
+                    ..GameCommon::new(leader)
+                },
+                state: Box::new(game),
+                mode: "test".to_owned(),
+                mode_info: info,
+                recording: None,
+            },
+            clock,
+            scheduled: EventQueue::new(clock_ref.clone()),
+            bots: HashMap::new(),
+            bot_turns: EventQueue::new(clock_ref),
+            disconnected: HashSet::new(),
+            broadcasts: HashSet::new(),
+            results: Vec::new(),
+            notifications: Vec::new(),
+            chat: Vec::new(),
+        };
+
+        if let Some(hz) = tick_rate {
+            harness.lobby.set_tick_rate(hz);
+        }
+        harness
+            .lobby
+            .common
+            .connections
+            .insert(leader, PlayerConnection::new());
+        let updates = harness.lobby.on_join(leader).always_publish();
+        harness.apply(updates);
+        harness
+    }
+
+    pub fn leader(&self) -> PlayerId {
+        self.lobby.common.leader
+    }
+
+    pub fn lobby(&self) -> &Lobby {
+        &self.lobby
+    }
+
+    pub fn common(&self) -> &GameCommon {
+        &self.lobby.common
+    }
+
+    pub fn clock(&self) -> &ManualClock {
+        &self.clock
+    }
+
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Replace the lobby seed to get the same random numbers on every run
+    pub fn set_seed(&mut self, seed: Seed) {
+        self.lobby.common.rng = LobbyRng::from_seed(seed);
+    }
+
+    /// Record inputs from now on, see [`crate::replay`]
+    pub fn start_recording(&mut self) {
+        self.lobby.start_recording();
+    }
+
+    /// Log as exported to the leader
+    pub fn game_log(&self) -> Result<&GameLog, ErrorReply> {
+        self.lobby.game_log()
+    }
+
+    /// Join a new player to the lobby
+    pub fn add_player(&mut self) -> Result<PlayerId, ErrorReply> {
+        let player = PlayerId::new();
+        self.join(player)?;
+        Ok(player)
+    }
+
+    pub fn join(&mut self, player: PlayerId) -> Result<(), ErrorReply> {
+        if !self.lobby.can_join() {
+            return Err(ErrorReply::CannotJoin);
+        }
+
+        self.lobby.common.players.insert(player);
+        self.lobby
+            .common
+            .connections
+            .insert(player, PlayerConnection::new());
+        let updates = self.lobby.on_join(player).always_publish();
+        let updates = updates.merge(self.lobby.post_chat(ChatLine::Joined(player)));
+        self.apply(updates);
+        Ok(())
+    }
+
+    /// Change the profile of a member
+    pub fn set_profile(&mut self, player: PlayerId, profile: Profile) -> Result<(), ErrorReply> {
+        if !self.lobby.common.players.contains(&player) {
+            return Err(ErrorReply::NotInThatGame);
+        }
+        let updates = self.lobby.set_profile(player, profile);
+        self.apply(updates);
+        Ok(())
+    }
+
+    /// Join a bot, acting `think_delay` after each state it receives
+    pub fn add_bot<B: Bot + 'static>(
+        &mut self,
+        bot: B,
+        think_delay: Duration,
+    ) -> Result<PlayerId, ErrorReply> {
+        if !self.lobby.can_join() {
+            return Err(ErrorReply::CannotJoin);
+        }
+
+        let bot_id = PlayerId::new();
+        let profile = bot.profile();
+        let bot = BotPlayer::new(self.game_id, Box::new(bot), think_delay);
+        self.bots.insert(bot_id, bot);
+        let updates = self.lobby.add_bot(bot_id, profile);
+        self.apply(updates);
+        Ok(bot_id)
+    }
+
+    pub fn leave(&mut self, player: PlayerId) -> Result<(), ErrorReply> {
+        let updates = self.lobby.leave(player)?;
+        self.forget_bot(player);
+        self.disconnected.remove(&player);
+        self.apply(updates);
+        Ok(())
+    }
+
+    /// Kick a player or bot, as the leader
+    pub fn kick(&mut self, player: PlayerId) -> Result<(), ErrorReply> {
+        let updates = self.lobby.kick(player)?;
+        self.forget_bot(player);
+        self.disconnected.remove(&player);
+        self.apply(updates);
+        Ok(())
+    }
+
+    /// Lose the connection, keeping the player in the lobby
+    pub fn disconnect(&mut self, player: PlayerId) {
+        self.disconnected.insert(player);
+        let updates = self.lobby.disconnect(player).always_publish();
+        self.apply(updates);
+    }
+
+    pub fn reconnect(&mut self, player: PlayerId) -> Result<(), ErrorReply> {
+        if !self.lobby.can_reconnect() {
+            return Err(ErrorReply::CannotJoin);
+        }
+
+        self.disconnected.remove(&player);
+        let updates = self
+            .lobby
+            .reconnect(player, PlayerConnection::new())
+            .always_publish();
+        self.apply(updates);
+        Ok(())
+    }
+
+    /// Simulate a heartbeat round-trip time measurement
+    pub fn measure_rtt(&mut self, player: PlayerId, rtt: Duration) {
+        let Some(mut connection) = self.lobby.common.connections.get(&player).cloned() else {
+            return;
+        };
+        connection.sample(rtt);
+        let updates = self.lobby.update_connection(player, connection);
+        self.apply(updates);
+    }
+
+    /// Start the game as the leader
+    pub fn start(&mut self) -> Result<(), ErrorReply> {
+        let updates = self.lobby.start()?;
+        self.apply(updates);
+        Ok(())
+    }
+
+    pub fn set_ready(&mut self, player: PlayerId, ready: bool) -> Result<(), ErrorReply> {
+        let updates = self.lobby.set_ready(player, ready)?;
+        self.apply(updates);
+        Ok(())
+    }
+
+    /// Send an `Inner` message and return the raw reply
+    pub fn send_raw(
+        &mut self,
+        player: PlayerId,
+        message: serde_json::Value,
+    ) -> Result<serde_json::Value, serde_json::Value> {
+        assert!(
+            self.lobby.common.players.contains(&player),
+            "Player not in the lobby"
+        );
+
+        let (updates, reply) = self.lobby.on_message_from(player, message);
+        self.apply(updates);
+        reply
+    }
+
+    /// Send an `Inner` message and decode the reply.
+    /// Panics if the message can't be serialized or the reply doesn't match the types.
+    pub fn send<M, R, E>(&mut self, player: PlayerId, message: &M) -> Result<R, E>
+    where
+        M: Serialize,
+        R: DeserializeOwned,
+        E: DeserializeOwned,
+    {
+        let message = serde_json::to_value(message).expect("Unable to serialize message");
+        match self.send_raw(player, message) {
+            Ok(value) => Ok(serde_json::from_value(value).expect("Unexpected reply")),
+            Err(value) => Err(serde_json::from_value(value).expect("Unexpected error reply")),
+        }
+    }
+
+    pub fn public_state(&self) -> serde_json::Value {
+        self.lobby.public_state()
+    }
+
+    pub fn state_for_player(&self, player: PlayerId) -> serde_json::Value {
+        self.lobby.state_for_player(player)
+    }
+
+    /// Team-shared state as seen by `player`
+    pub fn state_for_team_of(&self, player: PlayerId) -> serde_json::Value {
+        self.lobby.state_for_team_of(player)
+    }
+
+    /// Move a player to a team, or out of all teams with `None`
+    pub fn set_team(&mut self, player: PlayerId, team: Option<&str>) -> Result<(), ErrorReply> {
+        let updates = self.lobby.set_team(player, team.map(str::to_owned))?;
+        self.apply(updates.always_publish());
+        Ok(())
+    }
+
+    /// Make another member the leader
+    pub fn promote(&mut self, player: PlayerId) -> Result<(), ErrorReply> {
+        let updates = self.lobby.promote(player)?;
+        self.apply(updates);
+        Ok(())
+    }
+
+    /// Post a chat message as a member
+    pub fn send_chat(&mut self, player: PlayerId, text: &str) -> Result<(), ErrorReply> {
+        let updates = self.lobby.send_chat(player, text.to_owned())?;
+        self.apply(updates);
+        Ok(())
+    }
+
+    /// Retained chat lines, as a member would get them
+    pub fn chat_history(&self, player: PlayerId) -> Result<Vec<ChatLine>, ErrorReply> {
+        self.lobby.chat_history(player)
+    }
+
+    /// Mute or unmute a member, as the leader
+    pub fn set_chat_muted(&mut self, player: PlayerId, muted: bool) -> Result<(), ErrorReply> {
+        let updates = self.lobby.set_chat_muted(player, muted)?;
+        self.apply(updates);
+        Ok(())
+    }
+
+    /// Move time forward, firing scheduled events and ticks in order
+    pub fn advance(&mut self, by: Duration) {
+        let until = self.clock.now() + by;
+        loop {
+            let next_tick = self.lobby.common.ticker.as_ref().map(|t| t.next_at);
+            let next = [
+                self.scheduled.next_timeout(),
+                next_tick,
+                self.bot_turns.next_timeout(),
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            match next {
+                Some(at) if at <= until => {
+                    self.clock.advance_to(at);
+                    self.run_due();
+                }
+                _ => break,
+            }
+        }
+        self.clock.advance_to(until);
+    }
+
+    /// Players that would have received the game state since the last call
+    pub fn take_broadcasts(&mut self) -> HashSet<PlayerId> {
+        std::mem::take(&mut self.broadcasts)
+    }
+
+    /// Assert the exact set of players that received the game state since the
+    /// last check, and reset it
+    #[track_caller]
+    pub fn assert_broadcast_to(&mut self, players: &[PlayerId]) {
+        let expected: HashSet<PlayerId> = players.iter().copied().collect();
+        assert_eq!(
+            self.take_broadcasts(),
+            expected,
+            "Unexpected broadcast recipients"
+        );
+    }
+
+    /// Results reported since the last call
+    pub fn take_results(&mut self) -> Vec<GameResult> {
+        std::mem::take(&mut self.results)
+    }
+
+    /// Notifications sent since the last call, with connected recipients sorted by id
+    pub fn take_notifications(&mut self) -> Vec<(Vec<PlayerId>, serde_json::Value)> {
+        std::mem::take(&mut self.notifications)
+    }
+
+    /// Lobby chat lines posted since the last call
+    pub fn take_chat(&mut self) -> Vec<ChatLine> {
+        std::mem::take(&mut self.chat)
+    }
+
+    fn run_due(&mut self) {
+        while let Some((at, (game_id, event_id), event)) = self.scheduled.pop_completed() {
+            if let Some((next_at, next)) = event.repeat(at) {
+                self.scheduled.add((game_id, event_id), next, next_at);
+            }
+            let event_updates = self.lobby.on_event(event_id, event.payload);
+            self.apply(event_updates);
+        }
+
+        while let Some((_, _, bot_id)) = self.bot_turns.pop_completed() {
+            let Some(bot) = self.bots.get_mut(&bot_id) else {
+                continue;
+            };
+            let updates = bot.take_turn(&mut self.lobby, bot_id);
+            self.apply(updates);
+        }
+
+        let now = self.clock.now();
+        let tick_due = self
+            .lobby
+            .common
+            .ticker
+            .as_ref()
+            .is_some_and(|ticker| ticker.next_at <= now);
+        if tick_due {
+            let updates = self.lobby.run_ticks(now);
+            self.apply_with(updates, true);
+        }
+    }
+
+    fn apply(&mut self, updates: Updates) {
+        self.apply_with(updates, false);
+    }
+
+    /// Mirrors `PublishGameState::apply` for a single lobby
+    fn apply_with(&mut self, updates: Updates, ticked: bool) {
+        let mut publish = PublishGameState::default();
+        updates.apply(self.game_id, &mut publish, &mut self.scheduled);
+
+        for (_, hz) in publish.tick_rates {
+            self.lobby.set_tick_rate(hz);
+        }
+
+        for (_, result) in publish.results {
+            self.results.push(result);
+        }
+
+        self.chat
+            .extend(publish.chat.into_iter().map(|(_, line)| line));
+
+        if let Some(recipients) = publish.games.remove(&self.game_id) {
+            self.publish_state(recipients, ticked);
+        }
+
+        for (_, notification) in publish.notifications {
+            let Some(notification) = self.lobby.defer_notification(notification, ticked) else {
+                continue;
+            };
+            let mut players = notification.to.resolve(&self.lobby.common);
+            players.retain(|player| !self.disconnected.contains(player));
+            self.notifications.push((players, notification.payload));
+        }
+    }
+
+    /// Record a state update to `recipients`, or to all members unless the
+    /// broadcast waits for the next tick
+    fn publish_state(&mut self, recipients: Option<HashSet<PlayerId>>, ticked: bool) {
+        let recipients = match recipients {
+            Some(_) if self.lobby.broadcast_pending() => return,
+            Some(players) => players,
+            None if self.lobby.defer_broadcast(ticked) => return,
+            None => self.lobby.common.players.clone(),
+        };
+
+        let recipients: Vec<PlayerId> = recipients
+            .into_iter()
+            .filter(|player| {
+                self.lobby.common.players.contains(player) && !self.disconnected.contains(player)
+            })
+            .collect();
+        for player in &recipients {
+            if let Some(bot) = self.bots.get_mut(player) {
+                let players = self.lobby.common.player_infos();
+                let public_state = self.lobby.public_state();
+                let view = BotView::new(self.game_id, &self.lobby, *player, players, public_state);
+                if let Some((wakeup, at)) = bot.observe(view, self.clock.now()) {
+                    self.bot_turns.add(wakeup, *player, at);
+                }
+            }
+        }
+        self.broadcasts.extend(recipients);
+    }
+
+    fn forget_bot(&mut self, player: PlayerId) {
+        if let Some(wakeup) = self.bots.remove(&player).and_then(|bot| bot.wakeup) {
+            self.bot_turns.cancel(&wakeup);
+        }
+    }
+}
+
+/// In-memory client connected directly to a [`ServerRemote`]
+pub struct TestClient {
+    tx: mpsc::UnboundedSender<Result<ClientMessage, serde_json::Error>>,
+    rx: mpsc::UnboundedReceiver<ServerMessage>,
+    /// Server-sent messages received while waiting for replies
+    events: VecDeque<ServerSentMessage>,
+    task: JoinHandle<()>,
+}
+
+impl TestClient {
+    pub async fn connect(server: &ServerRemote) -> Self {
+        let (client_tx, server_rx) = mpsc::unbounded_channel();
+        let (server_tx, client_rx) = mpsc::unbounded_channel();
+
+        let sink = sink::unfold(server_tx, |tx, message: ServerMessage| async move {
+            tx.send(message)?;
+            Ok::<_, mpsc::error::SendError<ServerMessage>>(tx)
+        });
+        let stream = stream::unfold(server_rx, |mut rx| async move {
+            let message = rx.recv().await?;
+            Some((message, rx))
+        });
+
+        let handle = server.make_client_handle(SocketAddr::from(([127, 0, 0, 1], 0)));
+        let task = tokio::spawn(handle.handle_client(sink, stream));
+
+        Self {
+            tx: client_tx,
+            rx: client_rx,
+            events: VecDeque::new(),
+            task,
+        }
+    }
+
+    /// Send a message and wait for its reply
+    pub async fn request(&mut self, data: ClientMessageData) -> ReplyMessage {
+        let message = data.finalize();
+        let id = message.id;
+        self.tx.send(Ok(message)).expect("Server has stopped");
+
+        loop {
+            match self.rx.recv().await.expect("Server has stopped") {
+                ServerMessage::ReplyTo(reply_to, reply) if reply_to == id => return reply,
+                ServerMessage::ReplyTo(_, _) => {}
+                ServerMessage::ServerSent(event) => self.events.push_back(event),
+            }
+        }
+    }
+
+    /// Send a message that fails to parse, as if the client sent invalid JSON
+    pub fn send_invalid(&mut self, text: &str) {
+        let error =
+            serde_json::from_str::<ClientMessage>(text).expect_err("Message should be invalid");
+        self.tx.send(Err(error)).expect("Server has stopped");
+    }
+
+    /// Wait for the next server-sent message
+    pub async fn next_event(&mut self) -> ServerSentMessage {
+        if let Some(event) = self.events.pop_front() {
+            return event;
+        }
+
+        loop {
+            match self.rx.recv().await.expect("Server has stopped") {
+                ServerMessage::ServerSent(event) => return event,
+                ServerMessage::ReplyTo(_, _) => {}
+            }
+        }
+    }
+
+    /// Wait for the next game state, skipping other server-sent messages
+    pub async fn next_game_info(&mut self) -> GameInfo {
+        loop {
+            if let ServerSentMessage::GameInfo(info) = self.next_event().await {
+                return *info;
+            }
+        }
+    }
+
+    /// Server-sent messages received so far, without waiting
+    pub fn drain_events(&mut self) -> Vec<ServerSentMessage> {
+        while let Ok(message) = self.rx.try_recv() {
+            if let ServerMessage::ServerSent(event) = message {
+                self.events.push_back(event);
+            }
+        }
+        self.events.drain(..).collect()
+    }
+
+    /// Close the connection and wait until the server has seen it
+    pub async fn disconnect(self) {
+        drop(self.tx);
+        self.task.await.expect("Client task panicked");
+    }
+}
@@ -0,0 +1,409 @@
+//! Turn order and turn timers for turn-based games
+//!
+//! Keep a [`Turns`] in the game state, route [`Game::on_event`] calls through
+//! [`Turns::handle_event`], and return [`Turns::info`] from [`Game::turn_info`]
+//! to show the turn and remaining time to clients.
+//!
+//! [`Game::on_event`]: crate::game_state::Game::on_event
+//! [`Game::turn_info`]: crate::game_state::Game::turn_info
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use wgfw_protocol::{PlayerId, TurnInfo};
+
+use crate::clock::Clock;
+use crate::game_state::{EventId, Updates, MIN_INTERVAL};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TurnTimer {
+    /// Turns never time out
+    Unlimited,
+    /// Each turn has the same time limit, at least [`MIN_INTERVAL`]
+    PerTurn(Duration),
+    /// Each player has a time bank that only runs during their own turns.
+    /// A player whose bank runs out is flagged: their seat becomes inactive
+    /// and their turns no longer time out.
+    ChessClock {
+        initial: Duration,
+        /// Added to the bank after each completed turn
+        increment: Duration,
+    },
+}
+
+/// Seat order, current turn and turn timer
+#[derive(Debug)]
+pub struct Turns {
+    seats: Vec<PlayerId>,
+    /// Index to `seats`
+    current: Option<usize>,
+    /// Seats that are skipped when advancing, e.g. eliminated players
+    inactive: HashSet<PlayerId>,
+    /// Passes in a row, reset by a normal turn
+    consecutive_passes: usize,
+    timer: TurnTimer,
+    /// Remaining time banks in chess clock mode
+    clocks: HashMap<PlayerId, Duration>,
+    turn_started: Option<Instant>,
+    /// Timeout of the current turn
+    deadline: Option<(Instant, EventId)>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Turns {
+    /// Use the lobby clock from [`GameCommon::clock`](crate::game_state::GameCommon::clock)
+    pub fn new(seats: Vec<PlayerId>, timer: TurnTimer, clock: Arc<dyn Clock>) -> Self {
+        let clocks = match timer {
+            TurnTimer::ChessClock { initial, .. } => {
+                seats.iter().map(|player| (*player, initial)).collect()
+            }
+            _ => HashMap::new(),
+        };
+
+        Self {
+            seats,
+            current: None,
+            inactive: HashSet::new(),
+            consecutive_passes: 0,
+            timer,
+            clocks,
+            turn_started: None,
+            deadline: None,
+            clock,
+        }
+    }
+
+    pub fn seats(&self) -> &[PlayerId] {
+        &self.seats
+    }
+
+    /// Player whose turn it is, `None` before the first turn
+    pub fn current(&self) -> Option<PlayerId> {
+        Some(self.seats[self.current?])
+    }
+
+    pub fn is_turn_of(&self, player: PlayerId) -> bool {
+        self.current() == Some(player)
+    }
+
+    pub fn consecutive_passes(&self) -> usize {
+        self.consecutive_passes
+    }
+
+    /// Start the first turn at the given seat
+    pub fn start(&mut self, first: usize, updates: &mut Updates) {
+        if self.seats.is_empty() {
+            return;
+        }
+
+        let first = first % self.seats.len();
+        self.current = Some(first);
+        if !self.is_active(self.seats[first]) {
+            self.advance();
+        }
+        self.consecutive_passes = 0;
+        self.begin_turn(updates);
+    }
+
+    /// The current player has completed their turn
+    pub fn end_turn(&mut self, updates: &mut Updates) {
+        self.consecutive_passes = 0;
+        self.next_turn(updates);
+    }
+
+    /// The current player passes without acting
+    pub fn pass(&mut self, updates: &mut Updates) {
+        self.consecutive_passes += 1;
+        self.next_turn(updates);
+    }
+
+    /// Mark a seat as skipped or no longer skipped
+    pub fn set_active(&mut self, player: PlayerId, active: bool) {
+        if active {
+            self.inactive.remove(&player);
+        } else {
+            self.inactive.insert(player);
+        }
+    }
+
+    pub fn is_active(&self, player: PlayerId) -> bool {
+        !self.inactive.contains(&player)
+    }
+
+    /// Remove a seat entirely. Ends the turn if it was theirs.
+    pub fn remove_seat(&mut self, player: PlayerId, updates: &mut Updates) {
+        let Some(index) = self.seats.iter().position(|p| *p == player) else {
+            return;
+        };
+
+        let was_current = self.current == Some(index);
+        self.seats.remove(index);
+        self.clocks.remove(&player);
+        self.inactive.remove(&player);
+
+        if self.seats.is_empty() {
+            self.current = None;
+            if let Some((_, id)) = self.deadline.take() {
+                updates.cancel(id);
+            }
+            return;
+        }
+
+        if let Some(current) = self.current {
+            if was_current {
+                // Continue from the previous seat, without charging anyone for the time used
+                self.current = Some((index + self.seats.len() - 1) % self.seats.len());
+                self.turn_started = None;
+                self.next_turn(updates);
+            } else if current > index {
+                self.current = Some(current - 1);
+            }
+        }
+    }
+
+    /// Handle a scheduled event. If the current turn timed out, the turn is
+    /// passed and the player who ran out of time is returned. In chess clock
+    /// mode, the player is also flagged.
+    pub fn handle_event(&mut self, id: EventId, updates: &mut Updates) -> Option<PlayerId> {
+        let (_, deadline_id) = self.deadline?;
+        if deadline_id != id {
+            return None;
+        }
+
+        let player = self.current()?;
+        if let Some(clock) = self.clocks.get_mut(&player) {
+            *clock = Duration::ZERO;
+            self.turn_started = None;
+            self.inactive.insert(player);
+        }
+        self.pass(updates);
+        Some(player)
+    }
+
+    /// The player has run out of chess clock time
+    pub fn is_flagged(&self, player: PlayerId) -> bool {
+        self.clocks.get(&player).is_some_and(Duration::is_zero)
+    }
+
+    /// Time left in the current turn
+    pub fn turn_remaining(&self) -> Option<Duration> {
+        let (at, _) = self.deadline?;
+        Some(at.saturating_duration_since(self.clock.now()))
+    }
+
+    /// Remaining chess clock time of a player
+    pub fn clock_remaining(&self, player: PlayerId) -> Option<Duration> {
+        let bank = *self.clocks.get(&player)?;
+        if self.is_turn_of(player) {
+            if let Some(started) = self.turn_started {
+                return Some(bank.saturating_sub(self.clock.now() - started));
+            }
+        }
+        Some(bank)
+    }
+
+    /// Turn state as shown to clients
+    pub fn info(&self) -> TurnInfo {
+        TurnInfo {
+            seats: self.seats.clone(),
+            current: self.current(),
+            turn_remaining_ms: self.turn_remaining().map(|d| d.as_millis() as u64),
+            clocks_ms: self
+                .seats
+                .iter()
+                .filter_map(|player| {
+                    let remaining = self.clock_remaining(*player)?;
+                    Some((*player, remaining.as_millis() as u64))
+                })
+                .collect(),
+        }
+    }
+
+    fn next_turn(&mut self, updates: &mut Updates) {
+        if self.current.is_none() {
+            return;
+        }
+
+        // Charge the player for the time used
+        if let (Some(player), Some(started)) = (self.current(), self.turn_started) {
+            if let TurnTimer::ChessClock { increment, .. } = self.timer {
+                if let Some(bank) = self.clocks.get_mut(&player) {
+                    *bank = bank.saturating_sub(self.clock.now() - started) + increment;
+                }
+            }
+        }
+
+        self.advance();
+        self.begin_turn(updates);
+    }
+
+    /// Move to the next active seat, or stay put if nobody else is active
+    fn advance(&mut self) {
+        let Some(current) = self.current else {
+            return;
+        };
+
+        for offset in 1..=self.seats.len() {
+            let index = (current + offset) % self.seats.len();
+            if self.is_active(self.seats[index]) {
+                self.current = Some(index);
+                return;
+            }
+        }
+    }
+
+    fn begin_turn(&mut self, updates: &mut Updates) {
+        if let Some((_, id)) = self.deadline.take() {
+            updates.cancel(id);
+        }
+
+        let now = self.clock.now();
+        self.turn_started = Some(now);
+
+        let limit = match self.timer {
+            TurnTimer::Unlimited => None,
+            TurnTimer::PerTurn(limit) => Some(limit.max(MIN_INTERVAL)),
+            // An empty bank would time out right away, again and again
+            TurnTimer::ChessClock { .. } => self
+                .current()
+                .and_then(|p| self.clocks.get(&p).copied())
+                .filter(|bank| !bank.is_zero()),
+        };
+
+        self.deadline = limit.map(|limit| {
+            let at = now + limit;
+            (at, updates.add_timeout(at))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::ManualClock;
+
+    use super::*;
+
+    fn setup(count: usize, timer: TurnTimer) -> (Turns, Vec<PlayerId>, ManualClock) {
+        let clock = ManualClock::new();
+        let seats: Vec<PlayerId> = (0..count).map(|_| PlayerId::new()).collect();
+        let turns = Turns::new(seats.clone(), timer, Arc::new(clock.clone()));
+        (turns, seats, clock)
+    }
+
+    fn deadline(turns: &Turns) -> EventId {
+        turns.deadline.expect("No deadline").1
+    }
+
+    #[test]
+    fn turns_rotate_over_active_seats() {
+        let (mut turns, seats, _) = setup(3, TurnTimer::Unlimited);
+        let mut updates = Updates::NONE;
+        assert_eq!(turns.current(), None);
+
+        turns.start(1, &mut updates);
+        assert_eq!(turns.current(), Some(seats[1]));
+        turns.end_turn(&mut updates);
+        turns.end_turn(&mut updates);
+        assert_eq!(turns.current(), Some(seats[0]));
+
+        turns.set_active(seats[1], false);
+        turns.end_turn(&mut updates);
+        assert_eq!(turns.current(), Some(seats[2]));
+    }
+
+    #[test]
+    fn passes_are_counted_until_a_turn_is_played() {
+        let (mut turns, seats, _) = setup(2, TurnTimer::Unlimited);
+        let mut updates = Updates::NONE;
+        turns.start(0, &mut updates);
+
+        turns.pass(&mut updates);
+        turns.pass(&mut updates);
+        assert_eq!(turns.consecutive_passes(), 2);
+        assert_eq!(turns.current(), Some(seats[0]));
+        turns.end_turn(&mut updates);
+        assert_eq!(turns.consecutive_passes(), 0);
+    }
+
+    #[test]
+    fn removing_the_current_seat_moves_the_turn_on() {
+        let (mut turns, seats, _) = setup(3, TurnTimer::PerTurn(Duration::from_secs(5)));
+        let mut updates = Updates::NONE;
+        turns.start(1, &mut updates);
+        let old_deadline = deadline(&turns);
+
+        turns.remove_seat(seats[1], &mut updates);
+        assert_eq!(turns.current(), Some(seats[2]));
+        assert_ne!(deadline(&turns), old_deadline);
+        assert!(updates.cancelled.contains(&old_deadline));
+
+        turns.remove_seat(seats[0], &mut updates);
+        assert_eq!(turns.current(), Some(seats[2]));
+        assert_eq!(turns.seats(), &[seats[2]]);
+    }
+
+    #[test]
+    fn chess_clock_charges_used_time_and_adds_the_increment() {
+        let timer = TurnTimer::ChessClock {
+            initial: Duration::from_secs(10),
+            increment: Duration::from_secs(2),
+        };
+        let (mut turns, seats, clock) = setup(2, timer);
+        let mut updates = Updates::NONE;
+        turns.start(0, &mut updates);
+
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(
+            turns.clock_remaining(seats[0]),
+            Some(Duration::from_secs(7))
+        );
+        turns.end_turn(&mut updates);
+        assert_eq!(
+            turns.clock_remaining(seats[0]),
+            Some(Duration::from_secs(9))
+        );
+        assert_eq!(
+            turns.clock_remaining(seats[1]),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(turns.turn_remaining(), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn chess_clock_timeout_flags_the_player() {
+        let timer = TurnTimer::ChessClock {
+            initial: Duration::from_secs(1),
+            increment: Duration::ZERO,
+        };
+        let (mut turns, seats, clock) = setup(2, timer);
+        let mut updates = Updates::NONE;
+        turns.start(0, &mut updates);
+
+        clock.advance(Duration::from_secs(1));
+        let timed_out = turns.handle_event(deadline(&turns), &mut updates);
+        assert_eq!(timed_out, Some(seats[0]));
+        assert!(turns.is_flagged(seats[0]));
+        assert_eq!(turns.current(), Some(seats[1]));
+
+        // The flagged seat is skipped
+        turns.end_turn(&mut updates);
+        assert_eq!(turns.current(), Some(seats[1]));
+
+        // Nobody left with time: no deadline to fire again
+        clock.advance(Duration::from_secs(1));
+        turns.handle_event(deadline(&turns), &mut updates);
+        assert!(turns.is_flagged(seats[1]));
+        assert_eq!(turns.turn_remaining(), None);
+    }
+
+    #[test]
+    fn zero_turn_limit_is_clamped() {
+        let (mut turns, _, _) = setup(2, TurnTimer::PerTurn(Duration::ZERO));
+        let mut updates = Updates::NONE;
+        turns.start(0, &mut updates);
+        assert_eq!(turns.turn_remaining(), Some(MIN_INTERVAL));
+    }
+}
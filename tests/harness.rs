@@ -0,0 +1,896 @@
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use wgfw::bots::{Bot, BotView};
+use wgfw::game_state::{EventId, Game, GameCommon, Updates, MAX_TICK_RATE, MIN_INTERVAL};
+use wgfw::latency::{ConnectionQuality, QualityThresholds};
+use wgfw::protocol::{ChatLine, ErrorReply, LobbyPhase, Profile, TeamInfo};
+use wgfw::replay;
+use wgfw::rng::Seed;
+use wgfw::testing::GameHarness;
+use wgfw::{GameModeInfo, GameRegistry, PlayerId};
+
+/// Finishes when any player sends "finish"
+#[derive(Default)]
+struct Finisher;
+
+impl Game for Finisher {
+    fn on_message_from(
+        &mut self,
+        _common: &GameCommon,
+        _player: PlayerId,
+        message: Value,
+    ) -> (Updates, Result<Value, Value>) {
+        if message == json!("finish") {
+            (Updates::CHANGED.finish(), Ok(Value::Null))
+        } else {
+            (Updates::NONE, Err(json!("unknown message")))
+        }
+    }
+
+    fn public_state(&self, _common: &GameCommon) -> Value {
+        Value::Null
+    }
+
+    fn state_for_player(&self, _common: &GameCommon, _player: PlayerId) -> Value {
+        Value::Null
+    }
+}
+
+#[test]
+fn game_log_hides_seed_until_reveal() {
+    let info = GameModeInfo {
+        commit_reveal: true,
+        ..GameModeInfo::default()
+    };
+    let mut harness = GameHarness::with_info(Finisher, info);
+    let leader = harness.leader();
+    harness.start_recording();
+    assert!(harness.game_log().is_ok());
+
+    harness.start().unwrap();
+    assert!(matches!(harness.game_log(), Err(ErrorReply::WrongPhase)));
+
+    harness.send_raw(leader, json!("finish")).unwrap();
+    let revealed = harness.common().seed_commitment.as_ref().unwrap();
+    assert!(revealed.seed.is_some());
+    assert!(harness.game_log().is_ok());
+}
+
+fn hex(seed: &Seed) -> String {
+    seed.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[test]
+fn log_between_games_does_not_predict_the_next_seed() {
+    let info = GameModeInfo {
+        commit_reveal: true,
+        ..GameModeInfo::default()
+    };
+    let lobby_seed: Seed = [7; 32];
+    let mut harness = GameHarness::with_info(Finisher, info);
+    let leader = harness.leader();
+    harness.set_seed(lobby_seed);
+    harness.start_recording();
+    harness.start().unwrap();
+    harness.send_raw(leader, json!("finish")).unwrap();
+
+    let log = harness.game_log().unwrap().clone();
+    let mut seeds = vec![log.game_seed];
+    for entry in &log.entries {
+        if let replay::Input::Start(seed) = entry.input {
+            seeds.push(seed);
+        }
+    }
+    assert!(!seeds.contains(&lobby_seed));
+    let text = serde_json::to_string(&log).unwrap();
+    assert!(!text.contains(&serde_json::to_string(&lobby_seed).unwrap()));
+
+    harness.start().unwrap();
+    harness.send_raw(leader, json!("finish")).unwrap();
+    let revealed = harness
+        .common()
+        .seed_commitment
+        .clone()
+        .unwrap()
+        .seed
+        .unwrap();
+    assert!(seeds.iter().all(|seed| hex(seed) != revealed));
+
+    let mut registry = GameRegistry::new();
+    registry.register("test", Box::new(|| Box::new(Finisher)));
+    let lobby = replay::replay(&registry, harness.game_log().unwrap()).unwrap();
+    assert_eq!(hex(&lobby.common.rng.game_seed()), revealed);
+}
+
+#[test]
+fn replay_keeps_the_recorded_leader() {
+    let mut harness = GameHarness::new(Finisher);
+    harness.start_recording();
+    let leader = harness.leader();
+    let first = harness.add_player().unwrap();
+    let second = harness.add_player().unwrap();
+    let third = harness.add_player().unwrap();
+    harness.leave(leader).unwrap();
+    let new_leader = harness.leader();
+    harness.kick(new_leader).unwrap();
+    let promoted = *[first, second, third]
+        .iter()
+        .find(|player| **player != harness.leader() && **player != new_leader)
+        .unwrap();
+    harness.promote(promoted).unwrap();
+
+    let mut registry = GameRegistry::new();
+    registry.register("test", Box::new(|| Box::new(Finisher)));
+    let lobby = replay::replay(&registry, harness.game_log().unwrap()).unwrap();
+    assert_eq!(lobby.common.leader, harness.leader());
+    assert_eq!(lobby.common.players, harness.common().players);
+    assert_eq!(lobby.common.leader, promoted);
+}
+
+/// Shows the lobby data the game can see besides its own state
+#[derive(Default)]
+struct Roster;
+
+impl Game for Roster {
+    fn on_message_from(
+        &mut self,
+        _common: &GameCommon,
+        _player: PlayerId,
+        _message: Value,
+    ) -> (Updates, Result<Value, Value>) {
+        (Updates::NONE, Err(json!("unknown message")))
+    }
+
+    fn public_state(&self, common: &GameCommon) -> Value {
+        let mut names: Vec<_> = common
+            .profiles
+            .values()
+            .map(|profile| profile.display_name.clone())
+            .collect();
+        names.sort();
+        json!({
+            "bots": common.bots.len(),
+            "ready": common.ready.len(),
+            "names": names,
+        })
+    }
+
+    fn state_for_player(&self, _common: &GameCommon, _player: PlayerId) -> Value {
+        Value::Null
+    }
+}
+
+fn profile(name: &str) -> Profile {
+    Profile {
+        display_name: name.to_owned(),
+        avatar: None,
+        color: None,
+    }
+}
+
+#[test]
+fn replay_restores_bots_profiles_and_ready_checks() {
+    let info = GameModeInfo {
+        min_players: 3,
+        ready_check: true,
+        ..GameModeInfo::default()
+    };
+    let mut harness = GameHarness::with_info(Roster, info.clone());
+    harness.start_recording();
+    let leader = harness.leader();
+    let guest = harness.add_player().unwrap();
+    let bot = harness.add_bot(FinishingBot, Duration::ZERO).unwrap();
+    harness.set_profile(guest, profile("guest")).unwrap();
+    harness.start().unwrap();
+    harness.set_ready(leader, true).unwrap();
+    harness.leave(guest).unwrap();
+    assert_eq!(harness.lobby().common.phase, LobbyPhase::Waiting);
+    harness.join(guest).unwrap();
+    harness.start().unwrap();
+    harness.set_ready(guest, true).unwrap();
+    harness.set_ready(leader, true).unwrap();
+    assert_eq!(harness.lobby().common.phase, LobbyPhase::Running);
+
+    let mut registry = GameRegistry::new();
+    registry.register_with_info("test", Box::new(|| Box::new(Roster)), info);
+    let lobby = replay::replay(&registry, harness.game_log().unwrap()).unwrap();
+    assert_eq!(lobby.common.phase, LobbyPhase::Running);
+    assert_eq!(lobby.common.bots, [bot].into_iter().collect());
+    assert_eq!(lobby.public_state(), harness.public_state());
+}
+
+#[test]
+fn logs_started_mid_lobby_are_refused() {
+    let mut harness = GameHarness::new(Finisher);
+    harness.add_player().unwrap();
+    harness.start_recording();
+
+    let mut registry = GameRegistry::new();
+    registry.register("test", Box::new(|| Box::new(Finisher)));
+    let result = replay::replay(&registry, harness.game_log().unwrap());
+    assert!(matches!(result, Err(replay::ReplayError::MidLobby)));
+}
+
+/// Counts ticks and the events of an optional interval started with the game
+#[derive(Default)]
+struct Counter {
+    interval: Option<Duration>,
+    fired: u32,
+}
+
+impl Game for Counter {
+    fn on_start(&mut self, _common: &GameCommon) -> Updates {
+        let mut updates = Updates::NONE;
+        if let Some(period) = self.interval {
+            updates.add_interval_with(period, &());
+        }
+        updates
+    }
+
+    fn on_event(&mut self, _common: &GameCommon, _id: EventId, _payload: Value) -> Updates {
+        self.fired += 1;
+        Updates::CHANGED
+    }
+
+    fn on_tick(&mut self, _common: &GameCommon, _dt: Duration) -> Updates {
+        self.fired += 1;
+        Updates::CHANGED
+    }
+
+    fn on_message_from(
+        &mut self,
+        _common: &GameCommon,
+        _player: PlayerId,
+        _message: Value,
+    ) -> (Updates, Result<Value, Value>) {
+        (Updates::NONE, Ok(json!(self.fired)))
+    }
+
+    fn public_state(&self, _common: &GameCommon) -> Value {
+        Value::Null
+    }
+
+    fn state_for_player(&self, _common: &GameCommon, _player: PlayerId) -> Value {
+        Value::Null
+    }
+}
+
+fn fired(harness: &mut GameHarness) -> Value {
+    let leader = harness.leader();
+    harness.send_raw(leader, Value::Null).unwrap()
+}
+
+#[test]
+fn zero_interval_is_clamped() {
+    let mut harness = GameHarness::new(Counter {
+        interval: Some(Duration::ZERO),
+        fired: 0,
+    });
+    harness.start().unwrap();
+    harness.advance(MIN_INTERVAL * 10);
+    assert_eq!(fired(&mut harness), json!(10));
+}
+
+#[test]
+fn tick_rate_is_clamped() {
+    let info = GameModeInfo {
+        tick_rate: Some(u32::MAX),
+        ..GameModeInfo::default()
+    };
+    let mut harness = GameHarness::with_info(Counter::default(), info);
+    harness.advance(Duration::from_secs(1) / MAX_TICK_RATE * 10);
+    assert_eq!(fired(&mut harness), json!(10));
+}
+
+#[test]
+fn zero_tick_rate_does_not_tick() {
+    let info = GameModeInfo {
+        tick_rate: Some(0),
+        ..GameModeInfo::default()
+    };
+    let mut harness = GameHarness::with_info(Counter::default(), info);
+    harness.advance(Duration::from_secs(1));
+    assert_eq!(fired(&mut harness), json!(0));
+    assert!(harness.common().ticker.is_none());
+}
+
+#[test]
+fn ticking_lobby_coalesces_broadcasts() {
+    let info = GameModeInfo {
+        tick_rate: Some(10),
+        ..GameModeInfo::default()
+    };
+    let mut harness = GameHarness::with_info(Counter::default(), info);
+    let leader = harness.leader();
+    harness.take_broadcasts();
+
+    let player = harness.add_player().unwrap();
+    harness.assert_broadcast_to(&[]);
+    harness.advance(Duration::from_millis(100));
+    harness.assert_broadcast_to(&[leader, player]);
+}
+
+/// Changes the state and notifies everyone of each message
+struct Announcer;
+
+impl Game for Announcer {
+    fn on_message_from(
+        &mut self,
+        _common: &GameCommon,
+        _player: PlayerId,
+        message: Value,
+    ) -> (Updates, Result<Value, Value>) {
+        let mut updates = Updates::CHANGED;
+        updates.notify_all(&message);
+        (updates, Ok(Value::Null))
+    }
+
+    fn public_state(&self, _common: &GameCommon) -> Value {
+        Value::Null
+    }
+
+    fn state_for_player(&self, _common: &GameCommon, _player: PlayerId) -> Value {
+        Value::Null
+    }
+}
+
+#[test]
+fn notifications_wait_for_the_deferred_broadcast() {
+    let info = GameModeInfo {
+        tick_rate: Some(10),
+        ..GameModeInfo::default()
+    };
+    let mut harness = GameHarness::with_info(Announcer, info);
+    let leader = harness.leader();
+    harness.take_broadcasts();
+
+    harness.send_raw(leader, json!("first")).unwrap();
+    harness.send_raw(leader, json!("second")).unwrap();
+    harness.assert_broadcast_to(&[]);
+    assert!(harness.take_notifications().is_empty());
+
+    harness.advance(Duration::from_millis(100));
+    harness.assert_broadcast_to(&[leader]);
+    assert_eq!(
+        harness.take_notifications(),
+        vec![
+            (vec![leader], json!("first")),
+            (vec![leader], json!("second"))
+        ]
+    );
+}
+
+#[test]
+fn notifications_without_ticks_are_sent_at_once() {
+    let mut harness = GameHarness::new(Announcer);
+    let leader = harness.leader();
+    harness.take_broadcasts();
+
+    harness.send_raw(leader, json!("now")).unwrap();
+    harness.assert_broadcast_to(&[leader]);
+    assert_eq!(
+        harness.take_notifications(),
+        vec![(vec![leader], json!("now"))]
+    );
+}
+
+/// Sends "finish" once the game is running
+struct FinishingBot;
+
+impl Bot for FinishingBot {
+    fn decide(&mut self, view: &BotView) -> Option<Value> {
+        (view.phase == LobbyPhase::Running).then(|| json!("finish"))
+    }
+}
+
+/// Remembers the leave and kick hooks called
+#[derive(Default)]
+struct Departures {
+    calls: Vec<Value>,
+}
+
+impl Game for Departures {
+    fn on_leave(&mut self, _common: &GameCommon, player: PlayerId) -> Updates {
+        self.calls.push(json!({ "leave": player }));
+        Updates::NONE
+    }
+
+    fn on_kick(&mut self, _common: &GameCommon, player: PlayerId) -> Updates {
+        self.calls.push(json!({ "kick": player }));
+        Updates::NONE
+    }
+
+    fn on_message_from(
+        &mut self,
+        _common: &GameCommon,
+        _player: PlayerId,
+        _message: Value,
+    ) -> (Updates, Result<Value, Value>) {
+        (Updates::NONE, Ok(json!(self.calls)))
+    }
+
+    fn public_state(&self, _common: &GameCommon) -> Value {
+        Value::Null
+    }
+
+    fn state_for_player(&self, _common: &GameCommon, _player: PlayerId) -> Value {
+        Value::Null
+    }
+}
+
+#[test]
+fn kicked_bots_run_the_kick_hook() {
+    let mut harness = GameHarness::new(Departures::default());
+    let leader = harness.leader();
+    let bot = harness
+        .add_bot(FinishingBot, Duration::from_secs(1))
+        .unwrap();
+    assert_eq!(harness.take_chat(), vec![ChatLine::Joined(bot)]);
+
+    harness.kick(bot).unwrap();
+    assert_eq!(harness.take_chat(), vec![ChatLine::Kicked(bot)]);
+    assert!(!harness.common().bots.contains(&bot));
+    let calls = harness.send_raw(leader, Value::Null).unwrap();
+    assert_eq!(calls, json!([{ "kick": bot }]));
+}
+
+#[test]
+fn bot_acts_after_think_delay() {
+    let mut harness = GameHarness::new(Finisher);
+    let bot = harness
+        .add_bot(FinishingBot, Duration::from_secs(1))
+        .unwrap();
+    harness.start().unwrap();
+
+    harness.advance(Duration::from_millis(999));
+    assert_eq!(harness.common().phase, LobbyPhase::Running);
+    harness.advance(Duration::from_millis(1));
+    assert_eq!(harness.common().phase, LobbyPhase::Finished);
+
+    harness.kick(bot).unwrap();
+    harness.start().unwrap();
+    harness.advance(Duration::from_secs(2));
+    assert_eq!(harness.common().phase, LobbyPhase::Running);
+}
+
+/// Remembers the reported connection qualities
+#[derive(Default)]
+struct QualityLog {
+    reported: Vec<ConnectionQuality>,
+}
+
+impl Game for QualityLog {
+    fn on_connection_quality(
+        &mut self,
+        _common: &GameCommon,
+        _player: PlayerId,
+        quality: ConnectionQuality,
+    ) -> Updates {
+        self.reported.push(quality);
+        Updates::NONE
+    }
+
+    fn on_message_from(
+        &mut self,
+        _common: &GameCommon,
+        _player: PlayerId,
+        _message: Value,
+    ) -> (Updates, Result<Value, Value>) {
+        (Updates::NONE, Ok(json!(self.reported)))
+    }
+
+    fn public_state(&self, _common: &GameCommon) -> Value {
+        Value::Null
+    }
+
+    fn state_for_player(&self, _common: &GameCommon, _player: PlayerId) -> Value {
+        Value::Null
+    }
+}
+
+#[test]
+fn reconnect_reports_recovered_quality() {
+    let info = GameModeInfo {
+        quality_thresholds: Some(QualityThresholds {
+            poor: Duration::from_millis(100),
+            bad: Duration::from_millis(300),
+        }),
+        ..GameModeInfo::default()
+    };
+    let mut harness = GameHarness::with_info(QualityLog::default(), info);
+    let leader = harness.leader();
+    harness.measure_rtt(leader, Duration::from_millis(500));
+    harness.disconnect(leader);
+    harness.reconnect(leader).unwrap();
+
+    let reported = harness.send_raw(leader, Value::Null).unwrap();
+    let expected = [ConnectionQuality::Bad, ConnectionQuality::Good];
+    assert_eq!(reported, json!(expected));
+}
+
+#[test]
+fn last_seen_follows_the_harness_clock() {
+    let mut harness = GameHarness::new(Finisher);
+    let leader = harness.leader();
+    harness.advance(Duration::from_secs(90));
+    harness.disconnect(leader);
+
+    let connection = &harness.common().connections[&leader];
+    assert!(!connection.connected);
+    assert_eq!(connection.last_seen, Some(90));
+}
+
+#[test]
+fn chat_announces_joins_leaves_and_kicks() {
+    let mut harness = GameHarness::new(Finisher);
+    let leader = harness.leader();
+    let guest = harness.add_player().unwrap();
+    let kicked = harness.add_player().unwrap();
+    harness.leave(guest).unwrap();
+    harness.kick(kicked).unwrap();
+
+    let lines = vec![
+        ChatLine::Joined(guest),
+        ChatLine::Joined(kicked),
+        ChatLine::Left(guest),
+        ChatLine::Kicked(kicked),
+    ];
+    assert_eq!(harness.take_chat(), lines);
+    let history = harness.chat_history(leader).unwrap();
+    assert_eq!(history[history.len() - lines.len()..], lines);
+    assert!(matches!(
+        harness.chat_history(guest),
+        Err(ErrorReply::NotInThatGame)
+    ));
+}
+
+#[test]
+fn chat_history_keeps_the_latest_lines() {
+    let mut harness = GameHarness::new(Finisher);
+    let leader = harness.leader();
+    for n in 0..=wgfw::chat::DEFAULT_HISTORY_LIMIT {
+        harness.send_chat(leader, &n.to_string()).unwrap();
+    }
+
+    let history = harness.chat_history(leader).unwrap();
+    assert_eq!(history.len(), wgfw::chat::DEFAULT_HISTORY_LIMIT);
+    let text = |line: &ChatLine| match line {
+        ChatLine::Message { text, .. } => text.clone(),
+        line => panic!("Unexpected line {line:?}"),
+    };
+    assert_eq!(text(&history[0]), "1");
+    assert_eq!(
+        text(history.last().unwrap()),
+        wgfw::chat::DEFAULT_HISTORY_LIMIT.to_string()
+    );
+}
+
+#[test]
+fn muted_players_cannot_chat() {
+    let mut harness = GameHarness::new(Finisher);
+    let guest = harness.add_player().unwrap();
+    harness.take_chat();
+
+    harness.set_chat_muted(guest, true).unwrap();
+    assert!(matches!(
+        harness.send_chat(guest, "hello"),
+        Err(ErrorReply::MutedInChat)
+    ));
+    // Muting again changes nothing and posts nothing
+    harness.set_chat_muted(guest, true).unwrap();
+    assert_eq!(harness.take_chat(), vec![ChatLine::Muted(guest)]);
+
+    harness.set_chat_muted(guest, false).unwrap();
+    harness.send_chat(guest, "hello").unwrap();
+    assert_eq!(
+        harness.take_chat(),
+        vec![
+            ChatLine::Unmuted(guest),
+            ChatLine::Message {
+                sender: guest,
+                text: "hello".to_owned(),
+            },
+        ]
+    );
+    assert!(matches!(
+        harness.set_chat_muted(PlayerId::new(), true),
+        Err(ErrorReply::NoSuchPlayer)
+    ));
+}
+
+/// Has the lobby chat turned off
+#[derive(Default)]
+struct Silent;
+
+impl Game for Silent {
+    fn on_message_from(
+        &mut self,
+        _common: &GameCommon,
+        _player: PlayerId,
+        _message: Value,
+    ) -> (Updates, Result<Value, Value>) {
+        (Updates::NONE, Err(json!("unknown message")))
+    }
+
+    fn public_state(&self, _common: &GameCommon) -> Value {
+        Value::Null
+    }
+
+    fn state_for_player(&self, _common: &GameCommon, _player: PlayerId) -> Value {
+        Value::Null
+    }
+
+    fn has_chat(&self, _common: &GameCommon) -> bool {
+        false
+    }
+}
+
+#[test]
+fn disabled_chat_is_refused() {
+    let mut harness = GameHarness::new(Silent);
+    let leader = harness.leader();
+    harness.add_player().unwrap();
+
+    assert!(harness.take_chat().is_empty());
+    assert!(matches!(
+        harness.send_chat(leader, "hello"),
+        Err(ErrorReply::ChatDisabled)
+    ));
+    assert!(matches!(
+        harness.chat_history(leader),
+        Err(ErrorReply::ChatDisabled)
+    ));
+}
+
+#[test]
+fn long_chat_messages_are_refused() {
+    let mut harness = GameHarness::new(Finisher);
+    let leader = harness.leader();
+    let text = "x".repeat(ChatLine::MAX_MESSAGE_LEN + 1);
+    assert!(matches!(
+        harness.send_chat(leader, &text),
+        Err(ErrorReply::ChatMessageTooLong)
+    ));
+}
+
+#[test]
+fn start_without_ready_check_runs_the_game() {
+    let mut harness = GameHarness::new(Finisher);
+    let leader = harness.leader();
+    assert_eq!(harness.lobby().common.phase, LobbyPhase::Waiting);
+
+    harness.start().unwrap();
+    assert_eq!(harness.lobby().common.phase, LobbyPhase::Running);
+    assert!(matches!(harness.start(), Err(ErrorReply::WrongPhase)));
+    assert!(matches!(
+        harness.set_ready(leader, true),
+        Err(ErrorReply::WrongPhase)
+    ));
+    // Joining is only allowed between games by default
+    assert!(matches!(harness.add_player(), Err(ErrorReply::CannotJoin)));
+
+    harness.send_raw(leader, json!("finish")).unwrap();
+    assert_eq!(harness.lobby().common.phase, LobbyPhase::Finished);
+    harness.add_player().unwrap();
+    harness.start().unwrap();
+    assert_eq!(harness.lobby().common.phase, LobbyPhase::Running);
+}
+
+#[test]
+fn ready_check_starts_once_everyone_is_ready() {
+    let info = GameModeInfo {
+        min_players: 2,
+        ready_check: true,
+        ..GameModeInfo::default()
+    };
+    let mut harness = GameHarness::with_info(Finisher, info);
+    let leader = harness.leader();
+    assert!(matches!(harness.start(), Err(ErrorReply::NotEnoughPlayers)));
+    let guest = harness.add_player().unwrap();
+    let bot = harness
+        .add_bot(FinishingBot, Duration::from_secs(1))
+        .unwrap();
+
+    harness.start().unwrap();
+    assert_eq!(harness.lobby().common.phase, LobbyPhase::ReadyCheck);
+    assert!(matches!(harness.start(), Err(ErrorReply::WrongPhase)));
+    // Bots are ready from the start
+    assert!(harness.common().ready.contains(&bot));
+
+    harness.set_ready(guest, true).unwrap();
+    harness.set_ready(guest, false).unwrap();
+    harness.set_ready(leader, true).unwrap();
+    assert_eq!(harness.lobby().common.phase, LobbyPhase::ReadyCheck);
+    assert!(!harness.common().ready.contains(&guest));
+
+    harness.set_ready(guest, true).unwrap();
+    assert_eq!(harness.lobby().common.phase, LobbyPhase::Running);
+    assert!(harness.common().ready.is_empty());
+}
+
+#[test]
+fn ready_check_is_called_off_without_enough_players() {
+    let info = GameModeInfo {
+        min_players: 2,
+        ready_check: true,
+        ..GameModeInfo::default()
+    };
+    let mut harness = GameHarness::with_info(Finisher, info);
+    let leader = harness.leader();
+    let guest = harness.add_player().unwrap();
+    harness.start().unwrap();
+    harness.set_ready(leader, true).unwrap();
+
+    harness.leave(guest).unwrap();
+    assert_eq!(harness.lobby().common.phase, LobbyPhase::Waiting);
+    assert!(harness.common().ready.is_empty());
+}
+
+#[test]
+fn full_lobbies_refuse_players() {
+    let info = GameModeInfo {
+        max_players: Some(2),
+        ..GameModeInfo::default()
+    };
+    let mut harness = GameHarness::with_info(Finisher, info);
+    harness.add_player().unwrap();
+    assert!(matches!(harness.add_player(), Err(ErrorReply::CannotJoin)));
+    assert!(matches!(
+        harness.add_bot(FinishingBot, Duration::ZERO),
+        Err(ErrorReply::CannotJoin)
+    ));
+}
+
+/// Schedules and cancels events on request and keeps the fired payloads
+#[derive(Default)]
+struct Alarms {
+    fired: Vec<Value>,
+}
+
+impl Game for Alarms {
+    fn on_event(&mut self, _common: &GameCommon, id: EventId, payload: Value) -> Updates {
+        self.fired.push(payload.clone());
+        let mut updates = Updates::NONE;
+        if let Some(limit) = payload["stop_after"].as_u64() {
+            let count = self.fired.iter().filter(|fired| **fired == payload).count();
+            if count as u64 >= limit {
+                updates.cancel(id);
+            }
+        }
+        updates
+    }
+
+    fn on_message_from(
+        &mut self,
+        _common: &GameCommon,
+        _player: PlayerId,
+        message: Value,
+    ) -> (Updates, Result<Value, Value>) {
+        let mut updates = Updates::NONE;
+        let reply = if let Some(ms) = message["delay"].as_u64() {
+            json!(updates.add_delay_with(Duration::from_millis(ms), &message["payload"]))
+        } else if let Some(ms) = message["interval"].as_u64() {
+            json!(updates.add_interval_with(Duration::from_millis(ms), &message["payload"]))
+        } else if let Ok(id) = serde_json::from_value::<EventId>(message["cancel"].clone()) {
+            updates.cancel(id);
+            Value::Null
+        } else {
+            json!(self.fired)
+        };
+        (updates, Ok(reply))
+    }
+
+    fn public_state(&self, _common: &GameCommon) -> Value {
+        Value::Null
+    }
+
+    fn state_for_player(&self, _common: &GameCommon, _player: PlayerId) -> Value {
+        Value::Null
+    }
+}
+
+#[test]
+fn events_deliver_their_json_payload() {
+    let mut harness = GameHarness::new(Alarms::default());
+    let leader = harness.leader();
+    let payload = json!({ "cards": [1, 2], "name": "draw", "extra": null });
+    harness
+        .send_raw(leader, json!({ "delay": 100, "payload": payload }))
+        .unwrap();
+
+    harness.advance(Duration::from_millis(99));
+    assert_eq!(harness.send_raw(leader, Value::Null).unwrap(), json!([]));
+    harness.advance(Duration::from_millis(1));
+    assert_eq!(
+        harness.send_raw(leader, Value::Null).unwrap(),
+        json!([payload])
+    );
+}
+
+#[test]
+fn cancelled_events_do_not_fire() {
+    let mut harness = GameHarness::new(Alarms::default());
+    let leader = harness.leader();
+    let cancelled = harness
+        .send_raw(leader, json!({ "delay": 100, "payload": "cancelled" }))
+        .unwrap();
+    harness
+        .send_raw(leader, json!({ "delay": 200, "payload": "kept" }))
+        .unwrap();
+    harness
+        .send_raw(leader, json!({ "cancel": cancelled }))
+        .unwrap();
+
+    harness.advance(Duration::from_secs(1));
+    assert_eq!(
+        harness.send_raw(leader, Value::Null).unwrap(),
+        json!(["kept"])
+    );
+    // Cancelling an event that has already fired does nothing
+    harness
+        .send_raw(leader, json!({ "cancel": cancelled }))
+        .unwrap();
+}
+
+#[test]
+fn periodic_events_can_cancel_themselves() {
+    let mut harness = GameHarness::new(Alarms::default());
+    let leader = harness.leader();
+    let payload = json!({ "stop_after": 3 });
+    harness
+        .send_raw(leader, json!({ "interval": 100, "payload": payload }))
+        .unwrap();
+
+    harness.advance(Duration::from_secs(2));
+    assert_eq!(
+        harness.send_raw(leader, Value::Null).unwrap(),
+        json!([payload, payload, payload])
+    );
+}
+
+/// Shows each team its name and size
+struct Huddle;
+
+impl Game for Huddle {
+    fn on_message_from(
+        &mut self,
+        _common: &GameCommon,
+        _player: PlayerId,
+        _message: Value,
+    ) -> (Updates, Result<Value, Value>) {
+        (Updates::NONE, Ok(Value::Null))
+    }
+
+    fn public_state(&self, _common: &GameCommon) -> Value {
+        Value::Null
+    }
+
+    fn state_for_player(&self, _common: &GameCommon, _player: PlayerId) -> Value {
+        Value::Null
+    }
+
+    fn state_for_team(&self, common: &GameCommon, team: &str) -> Value {
+        json!([team, common.teams.members(team).len()])
+    }
+}
+
+#[test]
+fn team_state_follows_the_team_of_the_player() {
+    let info = GameModeInfo {
+        teams: vec![TeamInfo::new("red", None), TeamInfo::new("blue", None)],
+        ..GameModeInfo::default()
+    };
+    let mut harness = GameHarness::with_info(Huddle, info);
+    let leader = harness.leader();
+    let guest = harness.add_player().unwrap();
+    assert_eq!(harness.state_for_team_of(leader), json!(["red", 1]));
+    assert_eq!(harness.state_for_team_of(guest), json!(["blue", 1]));
+
+    harness.set_team(guest, None).unwrap();
+    assert_eq!(harness.state_for_team_of(guest), Value::Null);
+    harness.set_team(guest, Some("red")).unwrap();
+    assert_eq!(harness.state_for_team_of(guest), json!(["red", 2]));
+    assert_eq!(harness.state_for_team_of(leader), json!(["red", 2]));
+}
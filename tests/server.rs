@@ -0,0 +1,603 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use warp::test::WsClient;
+use warp::ws::Ws;
+use warp::Filter;
+
+use wgfw::bots::{Bot, BotView};
+use wgfw::clock::{Clock, ManualClock};
+use wgfw::game_state::{Game, GameCommon, Updates};
+use wgfw::history::FileMatchStore;
+use wgfw::protocol::{
+    ChatLine, ClientMessageData, ErrorReply, GameId, GameInfo, GameResult, Identity, LobbyPhase,
+    Profile, ProfileError, ReplyMessage, ServerMessage, ServerSentMessage,
+};
+use wgfw::testing::TestClient;
+use wgfw::{Builder, GameModeInfo, PlayerId, ServerRemote};
+
+/// Shows the number of players, and each player their own id. Finishes
+/// with an empty result when a player sends "finish".
+#[derive(Default)]
+struct Seats;
+
+impl Game for Seats {
+    fn on_message_from(
+        &mut self,
+        _common: &GameCommon,
+        _player: PlayerId,
+        message: Value,
+    ) -> (Updates, Result<Value, Value>) {
+        if message == json!("finish") {
+            let updates = Updates::CHANGED.finish().with_result(GameResult::default());
+            (updates, Ok(Value::Null))
+        } else {
+            (Updates::NONE, Err(json!("unknown message")))
+        }
+    }
+
+    fn public_state(&self, common: &GameCommon) -> Value {
+        json!(common.players.len())
+    }
+
+    fn state_for_player(&self, _common: &GameCommon, player: PlayerId) -> Value {
+        json!(player)
+    }
+}
+
+async fn new_identity(client: &mut TestClient) -> PlayerId {
+    identity(client).await.player_id
+}
+
+async fn identity(client: &mut TestClient) -> Identity {
+    match client.request(ClientMessageData::NewIdentity).await {
+        ReplyMessage::Identity(identity) => identity,
+        reply => panic!("Unexpected reply {reply:?}"),
+    }
+}
+
+fn player_ids(info: &GameInfo) -> Vec<PlayerId> {
+    let mut ids: Vec<PlayerId> = info.players.iter().map(|player| player.id).collect();
+    ids.sort();
+    ids
+}
+
+#[tokio::test]
+async fn members_receive_their_game_info() {
+    let (_server, remote) = Builder::new().register::<Seats>("seats").spawn_server();
+    let mut leader = TestClient::connect(&remote).await;
+    let mut guest = TestClient::connect(&remote).await;
+    let leader_id = new_identity(&mut leader).await;
+    let guest_id = new_identity(&mut guest).await;
+
+    let reply = leader
+        .request(ClientMessageData::CreateGame("seats".to_owned()))
+        .await;
+    let ReplyMessage::GameCreated(game_id) = reply else {
+        panic!("Unexpected reply {reply:?}");
+    };
+    let info = leader.next_game_info().await;
+    assert_eq!(info.id, game_id);
+    assert_eq!(info.leader, leader_id);
+    assert_eq!(info.phase, LobbyPhase::Waiting);
+    assert_eq!(info.mode, "seats");
+    assert_eq!(player_ids(&info), vec![leader_id]);
+    assert_eq!(info.public_state, json!(1));
+    assert_eq!(info.private_state, json!(leader_id));
+
+    let reply = guest.request(ClientMessageData::JoinGame(game_id)).await;
+    assert!(matches!(reply, ReplyMessage::JoinedToGame(id) if id == game_id));
+    let mut members = vec![leader_id, guest_id];
+    members.sort();
+
+    let info = guest.next_game_info().await;
+    assert_eq!(info.id, game_id);
+    assert_eq!(info.leader, leader_id);
+    assert_eq!(player_ids(&info), members);
+    assert_eq!(info.public_state, json!(2));
+    assert_eq!(info.private_state, json!(guest_id));
+
+    let info = leader.next_game_info().await;
+    assert_eq!(player_ids(&info), members);
+    assert_eq!(info.public_state, json!(2));
+    assert_eq!(info.private_state, json!(leader_id));
+}
+
+#[tokio::test]
+async fn second_login_replaces_the_first_connection() {
+    let (_server, remote) = Builder::new().register::<Seats>("seats").spawn_server();
+    let mut first = TestClient::connect(&remote).await;
+    let mut second = TestClient::connect(&remote).await;
+    let identity = identity(&mut first).await;
+    let player_id = identity.player_id;
+
+    let reply = second.request(ClientMessageData::Identify(identity)).await;
+    assert!(matches!(reply, ReplyMessage::Identity(_)));
+    assert!(matches!(
+        first.next_event().await,
+        ServerSentMessage::Error { .. }
+    ));
+    first.disconnect().await;
+
+    let reply = second
+        .request(ClientMessageData::CreateGame("seats".to_owned()))
+        .await;
+    assert!(matches!(reply, ReplyMessage::GameCreated(_)));
+    assert_eq!(second.next_game_info().await.leader, player_id);
+}
+
+async fn connect_ws(remote: ServerRemote) -> WsClient {
+    let route = warp::ws().map(move |ws: Ws| {
+        let handle = remote.make_client_handle(SocketAddr::from(([127, 0, 0, 1], 0)));
+        ws.on_upgrade(|socket| handle.handle_ws_client(socket))
+    });
+    warp::test::ws().handshake(route).await.unwrap()
+}
+
+/// Next server message, skipping pings. `None` once the socket is closed.
+async fn recv_ws(client: &mut WsClient) -> Option<ServerMessage> {
+    loop {
+        let message = client.recv().await.ok()?;
+        if message.is_close() {
+            return None;
+        }
+        if let Ok(text) = message.to_str() {
+            return Some(serde_json::from_str(text).unwrap());
+        }
+    }
+}
+
+#[tokio::test]
+async fn second_login_closes_the_first_websocket() {
+    let (_server, remote) = Builder::new().register::<Seats>("seats").spawn_server();
+    let mut first = connect_ws(remote.clone()).await;
+    let mut second = TestClient::connect(&remote).await;
+
+    let request = ClientMessageData::NewIdentity.finalize();
+    first
+        .send_text(serde_json::to_string(&request).unwrap())
+        .await;
+    let Some(ServerMessage::ReplyTo(_, ReplyMessage::Identity(identity))) =
+        recv_ws(&mut first).await
+    else {
+        panic!("Expected an identity");
+    };
+
+    let reply = second.request(ClientMessageData::Identify(identity)).await;
+    assert!(matches!(reply, ReplyMessage::Identity(_)));
+    assert!(matches!(
+        recv_ws(&mut first).await,
+        Some(ServerMessage::ServerSent(ServerSentMessage::Error { .. }))
+    ));
+    let closed = tokio::time::timeout(Duration::from_secs(5), recv_ws(&mut first)).await;
+    assert!(matches!(closed, Ok(None)));
+}
+
+/// Never acts
+#[derive(Default)]
+struct Idle;
+
+impl Bot for Idle {
+    fn decide(&mut self, _view: &BotView) -> Option<Value> {
+        None
+    }
+}
+
+/// Wait for a line in the lobby chat, skipping other messages
+async fn next_chat(client: &mut TestClient) -> ChatLine {
+    loop {
+        if let ServerSentMessage::Chat { line, .. } = client.next_event().await {
+            return line;
+        }
+    }
+}
+
+#[tokio::test]
+async fn kicked_bots_are_announced_as_kicked() {
+    let (_server, remote) = Builder::new()
+        .register::<Seats>("seats")
+        .register_bot::<Idle>("seats", Duration::ZERO)
+        .spawn_server();
+    let mut leader = TestClient::connect(&remote).await;
+    let leader_id = new_identity(&mut leader).await;
+    let reply = leader
+        .request(ClientMessageData::CreateGame("seats".to_owned()))
+        .await;
+    let ReplyMessage::GameCreated(game_id) = reply else {
+        panic!("Unexpected reply {reply:?}");
+    };
+    assert_eq!(next_chat(&mut leader).await, ChatLine::Joined(leader_id));
+
+    let reply = leader.request(ClientMessageData::AddBot(game_id)).await;
+    let ReplyMessage::BotAdded(bot) = reply else {
+        panic!("Unexpected reply {reply:?}");
+    };
+    assert_eq!(next_chat(&mut leader).await, ChatLine::Joined(bot));
+
+    let reply = leader
+        .request(ClientMessageData::KickPlayer(game_id, bot, None))
+        .await;
+    assert!(matches!(reply, ReplyMessage::Ok));
+    assert_eq!(next_chat(&mut leader).await, ChatLine::Kicked(bot));
+    let info = leader.next_game_info().await;
+    assert_eq!(player_ids(&info), vec![leader_id]);
+}
+
+/// Next message about lobby membership, skipping states and chat
+async fn next_notice(client: &mut TestClient) -> ServerSentMessage {
+    loop {
+        match client.next_event().await {
+            ServerSentMessage::GameInfo(_) | ServerSentMessage::Chat { .. } => {}
+            event => return event,
+        }
+    }
+}
+
+async fn create_game(client: &mut TestClient) -> GameId {
+    let reply = client
+        .request(ClientMessageData::CreateGame("seats".to_owned()))
+        .await;
+    match reply {
+        ReplyMessage::GameCreated(game_id) => game_id,
+        reply => panic!("Unexpected reply {reply:?}"),
+    }
+}
+
+#[tokio::test]
+async fn members_are_told_about_joins_kicks_and_promotions() {
+    let (_server, remote) = Builder::new().register::<Seats>("seats").spawn_server();
+    let mut leader = TestClient::connect(&remote).await;
+    let mut guest = TestClient::connect(&remote).await;
+    new_identity(&mut leader).await;
+    let guest_id = new_identity(&mut guest).await;
+    let game_id = create_game(&mut leader).await;
+    assert!(matches!(
+        next_notice(&mut leader).await,
+        ServerSentMessage::Joined { id } if id == game_id
+    ));
+
+    guest.request(ClientMessageData::JoinGame(game_id)).await;
+    assert!(matches!(
+        next_notice(&mut guest).await,
+        ServerSentMessage::Joined { id } if id == game_id
+    ));
+    let reply = leader
+        .request(ClientMessageData::KickPlayer(
+            game_id,
+            guest_id,
+            Some("afk".to_owned()),
+        ))
+        .await;
+    assert!(matches!(reply, ReplyMessage::Ok));
+    assert!(matches!(
+        next_notice(&mut guest).await,
+        ServerSentMessage::Kicked { id, reason } if id == game_id && reason.as_deref() == Some("afk")
+    ));
+
+    guest.request(ClientMessageData::JoinGame(game_id)).await;
+    assert!(matches!(
+        next_notice(&mut guest).await,
+        ServerSentMessage::Joined { .. }
+    ));
+    let reply = leader.request(ClientMessageData::LeaveGame(game_id)).await;
+    assert!(matches!(reply, ReplyMessage::Ok));
+    assert!(matches!(
+        next_notice(&mut leader).await,
+        ServerSentMessage::Left { id } if id == game_id
+    ));
+    assert!(matches!(
+        next_notice(&mut guest).await,
+        ServerSentMessage::Promoted { id } if id == game_id
+    ));
+
+    let reply = guest.request(ClientMessageData::CloseGame(game_id)).await;
+    assert!(matches!(reply, ReplyMessage::Ok));
+    assert!(matches!(
+        next_notice(&mut guest).await,
+        ServerSentMessage::LobbyClosed { id } if id == game_id
+    ));
+}
+
+#[tokio::test]
+async fn leaving_a_lobby_requires_membership() {
+    let (_server, remote) = Builder::new().register::<Seats>("seats").spawn_server();
+    let mut leader = TestClient::connect(&remote).await;
+    let mut stranger = TestClient::connect(&remote).await;
+    new_identity(&mut leader).await;
+    new_identity(&mut stranger).await;
+    let game_id = create_game(&mut leader).await;
+
+    let reply = stranger
+        .request(ClientMessageData::LeaveGame(game_id))
+        .await;
+    assert!(matches!(
+        reply,
+        ReplyMessage::Error(ErrorReply::NotInThatGame)
+    ));
+}
+
+#[tokio::test]
+async fn lobbies_left_with_only_bots_are_closed() {
+    let (_server, remote) = Builder::new()
+        .register::<Seats>("seats")
+        .register_bot::<Idle>("seats", Duration::ZERO)
+        .spawn_server();
+    let mut leader = TestClient::connect(&remote).await;
+    let mut other = TestClient::connect(&remote).await;
+    let leader_id = new_identity(&mut leader).await;
+    new_identity(&mut other).await;
+
+    let left = create_game(&mut leader).await;
+    leader.request(ClientMessageData::AddBot(left)).await;
+    let reply = leader.request(ClientMessageData::LeaveGame(left)).await;
+    assert!(matches!(reply, ReplyMessage::Ok));
+
+    let kicked = create_game(&mut leader).await;
+    leader.request(ClientMessageData::AddBot(kicked)).await;
+    let reply = leader
+        .request(ClientMessageData::KickPlayer(kicked, leader_id, None))
+        .await;
+    assert!(matches!(reply, ReplyMessage::Ok));
+
+    for game_id in [left, kicked] {
+        let reply = other.request(ClientMessageData::JoinGame(game_id)).await;
+        assert!(matches!(
+            reply,
+            ReplyMessage::Error(ErrorReply::NoSuchGameLobby)
+        ));
+    }
+}
+
+#[tokio::test]
+async fn results_are_stored_with_the_server_clock() {
+    let path = std::env::temp_dir().join(format!("wgfw-server-{:?}.jsonl", GameId::new()));
+    let clock = ManualClock::new();
+    clock.advance(Duration::from_secs(90));
+    let (_server, remote) = Builder::new()
+        .register::<Seats>("seats")
+        .clock(clock.clone())
+        .match_store(FileMatchStore::new(&path))
+        .spawn_server();
+    let mut leader = TestClient::connect(&remote).await;
+    let leader_id = new_identity(&mut leader).await;
+    let game_id = create_game(&mut leader).await;
+    leader.request(ClientMessageData::StartGame(game_id)).await;
+    let reply = leader
+        .request(ClientMessageData::Inner(game_id, json!("finish")))
+        .await;
+    assert!(matches!(reply, ReplyMessage::Inner(_)));
+
+    let reply = leader.request(ClientMessageData::MatchHistory).await;
+    let ReplyMessage::MatchHistory(records) = reply else {
+        panic!("Unexpected reply {reply:?}");
+    };
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].finished_at, clock.unix_time());
+    assert_eq!(records[0].finished_at, 90);
+    assert_eq!(records[0].players, vec![leader_id]);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn invalid_profiles_are_rejected() {
+    let (_server, remote) = Builder::new().register::<Seats>("seats").spawn_server();
+    let mut client = TestClient::connect(&remote).await;
+    new_identity(&mut client).await;
+    let game_id = create_game(&mut client).await;
+
+    let profile = Profile {
+        display_name: "tab\tname".to_owned(),
+        avatar: None,
+        color: None,
+    };
+    let reply = client.request(ClientMessageData::SetProfile(profile)).await;
+    assert!(matches!(
+        reply,
+        ReplyMessage::Error(ErrorReply::InvalidProfile(
+            ProfileError::DisplayNameCharacters
+        ))
+    ));
+
+    let profile = Profile {
+        display_name: "x".repeat(Profile::MAX_DISPLAY_NAME_LEN + 1),
+        avatar: None,
+        color: None,
+    };
+    let reply = client.request(ClientMessageData::SetProfile(profile)).await;
+    assert!(matches!(
+        reply,
+        ReplyMessage::Error(ErrorReply::InvalidProfile(ProfileError::DisplayNameLength))
+    ));
+
+    // Rejected profiles are never shown, so the first one seen is the valid one
+    let profile = Profile {
+        display_name: "valid".to_owned(),
+        avatar: None,
+        color: None,
+    };
+    let reply = client
+        .request(ClientMessageData::SetProfile(profile.clone()))
+        .await;
+    assert!(matches!(reply, ReplyMessage::Ok));
+    loop {
+        let info = client.next_game_info().await;
+        assert_eq!(info.id, game_id);
+        if let Some(shown) = info.players[0].profile.clone() {
+            assert_eq!(shown, profile);
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn start_checks_the_player_count_of_the_mode() {
+    let duel = GameModeInfo {
+        min_players: 2,
+        max_players: Some(2),
+        ..GameModeInfo::default()
+    };
+    let (_server, remote) = Builder::new()
+        .register::<Seats>("seats")
+        .register_with_info::<Seats>("duel", duel)
+        .spawn_server();
+    let mut leader = TestClient::connect(&remote).await;
+    new_identity(&mut leader).await;
+    let game_id = create_game(&mut leader).await;
+
+    let reply = leader
+        .request(ClientMessageData::ChangeGameMode(
+            game_id,
+            "duel".to_owned(),
+        ))
+        .await;
+    assert!(matches!(reply, ReplyMessage::Ok));
+    let reply = leader.request(ClientMessageData::StartGame(game_id)).await;
+    assert!(matches!(
+        reply,
+        ReplyMessage::Error(ErrorReply::NotEnoughPlayers)
+    ));
+
+    let reply = leader
+        .request(ClientMessageData::ChangeGameMode(
+            game_id,
+            "seats".to_owned(),
+        ))
+        .await;
+    assert!(matches!(reply, ReplyMessage::Ok));
+    for _ in 0..2 {
+        let mut guest = TestClient::connect(&remote).await;
+        new_identity(&mut guest).await;
+        guest.request(ClientMessageData::JoinGame(game_id)).await;
+    }
+    let reply = leader
+        .request(ClientMessageData::ChangeGameMode(
+            game_id,
+            "duel".to_owned(),
+        ))
+        .await;
+    assert!(matches!(
+        reply,
+        ReplyMessage::Error(ErrorReply::TooManyPlayers)
+    ));
+    let reply = leader.request(ClientMessageData::StartGame(game_id)).await;
+    assert!(matches!(reply, ReplyMessage::Ok));
+}
+
+/// Counts "public" messages in the public state and "secret" messages of
+/// each player in their private state
+#[derive(Default)]
+struct Secrets {
+    public: u32,
+    secrets: HashMap<PlayerId, u32>,
+}
+
+impl Game for Secrets {
+    fn on_message_from(
+        &mut self,
+        _common: &GameCommon,
+        player: PlayerId,
+        message: Value,
+    ) -> (Updates, Result<Value, Value>) {
+        if message == json!("public") {
+            self.public += 1;
+            (Updates::CHANGED, Ok(Value::Null))
+        } else if message == json!("secret") {
+            *self.secrets.entry(player).or_default() += 1;
+            (Updates::private_for(&[player]), Ok(Value::Null))
+        } else {
+            (Updates::NONE, Err(json!("unknown message")))
+        }
+    }
+
+    fn public_state(&self, _common: &GameCommon) -> Value {
+        json!(self.public)
+    }
+
+    fn state_for_player(&self, _common: &GameCommon, player: PlayerId) -> Value {
+        json!(self.secrets.get(&player).copied().unwrap_or(0))
+    }
+}
+
+/// Send a message to the game. The reply also confirms that the updates of
+/// earlier messages have been sent.
+async fn send_inner(client: &mut TestClient, game_id: GameId, message: &str) {
+    client
+        .request(ClientMessageData::Inner(game_id, json!(message)))
+        .await;
+}
+
+fn game_infos(client: &mut TestClient) -> Vec<GameInfo> {
+    client
+        .drain_events()
+        .into_iter()
+        .filter_map(|event| match event {
+            ServerSentMessage::GameInfo(info) => Some(*info),
+            _ => None,
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn private_updates_reach_only_the_named_player() {
+    let (_server, remote) = Builder::new().register::<Secrets>("secrets").spawn_server();
+    let mut leader = TestClient::connect(&remote).await;
+    let mut guest = TestClient::connect(&remote).await;
+    new_identity(&mut leader).await;
+    new_identity(&mut guest).await;
+    let reply = leader
+        .request(ClientMessageData::CreateGame("secrets".to_owned()))
+        .await;
+    let ReplyMessage::GameCreated(game_id) = reply else {
+        panic!("Unexpected reply {reply:?}");
+    };
+    guest.request(ClientMessageData::JoinGame(game_id)).await;
+    send_inner(&mut guest, game_id, "public").await;
+    send_inner(&mut leader, game_id, "ping").await;
+    game_infos(&mut leader);
+    game_infos(&mut guest);
+
+    send_inner(&mut leader, game_id, "secret").await;
+    send_inner(&mut leader, game_id, "ping").await;
+    let infos = game_infos(&mut leader);
+    assert_eq!(infos.len(), 1);
+    assert_eq!(infos[0].public_state, json!(1));
+    assert_eq!(infos[0].private_state, json!(1));
+
+    send_inner(&mut guest, game_id, "ping").await;
+    assert!(game_infos(&mut guest).is_empty());
+}
+
+#[tokio::test]
+async fn private_updates_wait_for_a_pending_tick() {
+    let clock = ManualClock::new();
+    let info = GameModeInfo {
+        tick_rate: Some(10),
+        ..GameModeInfo::default()
+    };
+    let (_server, remote) = Builder::new()
+        .register_with_info::<Secrets>("secrets", info)
+        .clock(clock.clone())
+        .spawn_server();
+    let mut leader = TestClient::connect(&remote).await;
+    new_identity(&mut leader).await;
+    let reply = leader
+        .request(ClientMessageData::CreateGame("secrets".to_owned()))
+        .await;
+    let ReplyMessage::GameCreated(game_id) = reply else {
+        panic!("Unexpected reply {reply:?}");
+    };
+    clock.advance(Duration::from_millis(100));
+    assert_eq!(leader.next_game_info().await.public_state, json!(0));
+
+    send_inner(&mut leader, game_id, "public").await;
+    send_inner(&mut leader, game_id, "secret").await;
+    send_inner(&mut leader, game_id, "ping").await;
+    assert!(game_infos(&mut leader).is_empty());
+
+    clock.advance(Duration::from_millis(100));
+    let info = leader.next_game_info().await;
+    assert_eq!(info.public_state, json!(1));
+    assert_eq!(info.private_state, json!(1));
+}
@@ -20,6 +20,63 @@ impl WgfwEvents {
         *self.onupdate.lock().unwrap() = Some(value);
     }
 
+    /// Authenticate with this token instead of an anonymous identity.
+    /// Must be set before the connection opens.
+    #[wasm_bindgen(setter)]
+    pub fn set_auth_token(&self, value: String) {
+        *self.auth_token.lock().unwrap() = Some(value);
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_onchat(&self, value: js_sys::Function) {
+        *self.onchat.lock().unwrap() = Some(value);
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_onresult(&self, value: js_sys::Function) {
+        *self.onresult.lock().unwrap() = Some(value);
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_onevent(&self, value: js_sys::Function) {
+        *self.onevent.lock().unwrap() = Some(value);
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_onjoined(&self, value: js_sys::Function) {
+        *self.onjoined.lock().unwrap() = Some(value);
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_onleft(&self, value: js_sys::Function) {
+        *self.onleft.lock().unwrap() = Some(value);
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_onkicked(&self, value: js_sys::Function) {
+        *self.onkicked.lock().unwrap() = Some(value);
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_onpromoted(&self, value: js_sys::Function) {
+        *self.onpromoted.lock().unwrap() = Some(value);
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_onclosed(&self, value: js_sys::Function) {
+        *self.onclosed.lock().unwrap() = Some(value);
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_onqueue(&self, value: js_sys::Function) {
+        *self.onqueue.lock().unwrap() = Some(value);
+    }
+
+    #[wasm_bindgen(setter)]
+    pub fn set_onmatch(&self, value: js_sys::Function) {
+        *self.onmatch.lock().unwrap() = Some(value);
+    }
+
     #[wasm_bindgen(setter)]
     pub fn set_onerror(&self, value: js_sys::Function) {
         *self.onerror.lock().unwrap() = Some(value);
@@ -79,7 +136,57 @@ macro_rules! server_msg {
 // Messages to server
 server_msg!(GameModes, GameModes(v), game_modes);
 server_msg!(JoinedGames, JoinedGames(v), joined_games);
+server_msg!(MatchHistory, MatchHistory(v), match_history);
 server_msg!(CreateGame, GameCreated(v), create_game, game_type: String);
 server_msg!(JoinGame, JoinedToGame(v), join_game, game_id: GameId);
 server_msg!(LeaveGame, Ok, leave_game, game_id: GameId);
+server_msg!(KickPlayer, Ok, kick_player, game_id: GameId, player_id: PlayerId, reason: String);
+server_msg!(PromoteLeader, Ok, promote_leader, game_id: GameId, player_id: PlayerId);
+server_msg!(CloseGame, Ok, close_game, game_id: GameId);
+server_msg!(AddBot, BotAdded(v), add_bot, game_id: GameId);
+server_msg!(RemoveBot, Ok, remove_bot, game_id: GameId, player_id: PlayerId);
+server_msg!(JoinTeam, Ok, join_team, game_id: GameId, team: String);
+server_msg!(LeaveTeam, Ok, leave_team, game_id: GameId);
+server_msg!(AssignTeam, Ok, assign_team, game_id: GameId, player_id: PlayerId, team: String);
+server_msg!(StartGame, Ok, start_game, game_id: GameId);
+server_msg!(SetReady, Ok, set_ready, game_id: GameId, ready: bool);
+server_msg!(ChangeGameMode, Ok, change_game_mode, game_id: GameId, mode: String);
+server_msg!(SetProfile, Ok, set_profile, profile: Profile);
+server_msg!(SendChat, Ok, send_chat, game_id: GameId, text: String);
+server_msg!(ChatHistory, ChatHistory(v), chat_history, game_id: GameId);
+server_msg!(MuteChat, Ok, mute_chat, game_id: GameId, player_id: PlayerId);
+server_msg!(UnmuteChat, Ok, unmute_chat, game_id: GameId, player_id: PlayerId);
+server_msg!(ExportGameLog, GameLog(v), export_game_log, game_id: GameId);
 server_msg!(Inner, Inner(v), inner, game_id: GameId, inner: JsValue);
+server_msg!(LeaveQueue, Ok, leave_queue);
+
+#[wasm_bindgen]
+impl WgfwEvents {
+    /// Wait to be matched into a new lobby, see `onqueue` and `onmatch`
+    #[wasm_bindgen]
+    pub async fn queue_for_match(
+        &self,
+        mode: JsValue,
+        params: JsValue,
+    ) -> Result<JsValue, JsValue> {
+        let (tx, rx) = futures::channel::oneshot::channel::<ReplyMessage>();
+        crate::console_log!("Sending message \"queue_for_match\" {:?}", (&mode, &params));
+        self.send_message(
+            ClientMessageData::QueueForMatch {
+                mode: serde_wasm_bindgen::from_value(mode).expect("Failed to convert"),
+                params: serde_wasm_bindgen::from_value(params).expect("Failed to convert"),
+            },
+            Box::new(move |data| {
+                tx.send(data).unwrap();
+            }),
+        );
+
+        rx.await
+            .map(|value| match value {
+                ReplyMessage::Ok => Ok(JsValue::null()),
+                ReplyMessage::Error(err) => Err(JsValue::from_str(&format!("{:?}", err))),
+                _ => panic!("Unexpected reply"),
+            })
+            .unwrap()
+    }
+}
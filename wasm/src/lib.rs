@@ -12,7 +12,7 @@ use wasm_bindgen::prelude::*;
 use web_sys::{ErrorEvent, MessageEvent, WebSocket};
 
 use wgfw_protocol::{
-    ClientMessageData, ErrorReply, Identity, MessageId, ReplyMessage, ServerMessage,
+    ClientMessageData, ErrorReply, GameInfo, Identity, MessageId, ReplyMessage, ServerMessage,
     ServerSentMessage,
 };
 
@@ -33,10 +33,32 @@ extern "C" {
 pub struct WgfwEvents {
     ws: WebSocket,
     reply_callbacks: Arc<Mutex<HashMap<MessageId, Box<dyn FnOnce(ReplyMessage)>>>>,
+    /// Externally issued token, used instead of an anonymous identity if set
+    auth_token: Arc<Mutex<Option<String>>>,
     /// Ready and identified
     onready: Arc<Mutex<Option<js_sys::Function>>>,
     /// Received server-initiated message
     onupdate: Arc<Mutex<Option<js_sys::Function>>>,
+    /// Received a lobby chat line
+    onchat: Arc<Mutex<Option<js_sys::Function>>>,
+    /// A game has finished with a result
+    onresult: Arc<Mutex<Option<js_sys::Function>>>,
+    /// Received a game-specific notification
+    onevent: Arc<Mutex<Option<js_sys::Function>>>,
+    /// Added to a lobby
+    onjoined: Arc<Mutex<Option<js_sys::Function>>>,
+    /// Left a lobby
+    onleft: Arc<Mutex<Option<js_sys::Function>>>,
+    /// Removed from a lobby by the leader, with an optional reason
+    onkicked: Arc<Mutex<Option<js_sys::Function>>>,
+    /// Became the leader of a lobby
+    onpromoted: Arc<Mutex<Option<js_sys::Function>>>,
+    /// A joined lobby was deleted
+    onclosed: Arc<Mutex<Option<js_sys::Function>>>,
+    /// Place in the matchmaking queue changed
+    onqueue: Arc<Mutex<Option<js_sys::Function>>>,
+    /// Matchmaking joined a new lobby
+    onmatch: Arc<Mutex<Option<js_sys::Function>>>,
     /// Socket closed unexpectedly, matches both onerror and onclose callbacks
     onerror: Arc<Mutex<Option<js_sys::Function>>>,
 }
@@ -57,9 +79,20 @@ impl WgfwEvents {
         let self_ = Self {
             ws: WebSocket::new(&ws_url).expect("failed to open ws"),
             reply_callbacks: Arc::default(),
+            auth_token: Arc::default(),
             onready: Arc::default(),
             onerror: Arc::default(),
             onupdate: Arc::default(),
+            onchat: Arc::default(),
+            onresult: Arc::default(),
+            onevent: Arc::default(),
+            onjoined: Arc::default(),
+            onleft: Arc::default(),
+            onkicked: Arc::default(),
+            onpromoted: Arc::default(),
+            onclosed: Arc::default(),
+            onqueue: Arc::default(),
+            onmatch: Arc::default(),
         };
         self_.start_websocket().expect("error!");
         self_
@@ -93,8 +126,39 @@ impl WgfwEvents {
         );
     }
 
+    /// Identify using an externally issued token
+    fn authenticate(&self, token: String) {
+        let cloned_self = self.clone();
+        self.send_message(
+            ClientMessageData::Authenticate(token),
+            Box::new(move |reply| match reply {
+                ReplyMessage::Identity(identity) => {
+                    console_log!("Authenticated as: {:?}", identity);
+                    cloned_self.identify_done(identity);
+                }
+                ReplyMessage::Error(err) => {
+                    console_log!("Authentication failed: {:?}", err);
+                    if let Some(onerror) = cloned_self.onerror.lock().unwrap().as_ref() {
+                        onerror
+                            .call1(&JsValue::NULL, &JsValue::from_str(&format!("{:?}", err)))
+                            .expect("onerror errored");
+                    }
+                }
+                _ => {
+                    console_log!("Unexpected reply: {:?}", reply);
+                }
+            }),
+        );
+    }
+
     /// Must be called when connecting for the first time
     fn identify(&self) {
+        let token = self.auth_token.lock().unwrap().clone();
+        if let Some(token) = token {
+            self.authenticate(token);
+            return;
+        }
+
         // Get identity from local storage, if any. If not, request a new one.
         let cloned_self = self.clone();
         if let Some(old_identity) = storage::get_typed::<Identity>("wgfw_identity") {
@@ -152,13 +216,21 @@ impl WgfwEvents {
                         ServerSentMessage::Error { message } => {
                             console_log!("Error: {:?}", message);
                         }
-                        ServerSentMessage::GameInfo {
-                            id,
-                            leader,
-                            players,
-                            public_state,
-                            private_state,
-                        } => {
+                        ServerSentMessage::GameInfo(info) => {
+                            let GameInfo {
+                                id,
+                                leader,
+                                phase,
+                                players,
+                                public_state,
+                                private_state,
+                                mode,
+                                turn,
+                                tick,
+                                seed,
+                                teams,
+                                team_state,
+                            } = *info;
                             if let Some(onupdate) = cloned_self.onupdate.lock().unwrap().as_ref() {
                                 onupdate
                                     .apply(
@@ -170,6 +242,13 @@ impl WgfwEvents {
                                                 JsValue::from_serde(&players).unwrap(),
                                                 JsValue::from_serde(&public_state).unwrap(),
                                                 JsValue::from_serde(&private_state).unwrap(),
+                                                JsValue::from_serde(&phase).unwrap(),
+                                                JsValue::from_serde(&turn).unwrap(),
+                                                JsValue::from_serde(&tick).unwrap(),
+                                                JsValue::from_serde(&seed).unwrap(),
+                                                JsValue::from_serde(&mode).unwrap(),
+                                                JsValue::from_serde(&teams).unwrap(),
+                                                JsValue::from_serde(&team_state).unwrap(),
                                             ]
                                             .into_iter(),
                                         ),
@@ -177,6 +256,107 @@ impl WgfwEvents {
                                     .unwrap();
                             }
                         }
+                        ServerSentMessage::GameResult { id, result } => {
+                            if let Some(onresult) = cloned_self.onresult.lock().unwrap().as_ref() {
+                                onresult
+                                    .call2(
+                                        &JsValue::NULL,
+                                        &JsValue::from_serde(&id).unwrap(),
+                                        &JsValue::from_serde(&result).unwrap(),
+                                    )
+                                    .unwrap();
+                            }
+                        }
+                        ServerSentMessage::Chat { id, line } => {
+                            if let Some(onchat) = cloned_self.onchat.lock().unwrap().as_ref() {
+                                onchat
+                                    .call2(
+                                        &JsValue::NULL,
+                                        &JsValue::from_serde(&id).unwrap(),
+                                        &JsValue::from_serde(&line).unwrap(),
+                                    )
+                                    .unwrap();
+                            }
+                        }
+                        ServerSentMessage::GameEvent { id, payload } => {
+                            if let Some(onevent) = cloned_self.onevent.lock().unwrap().as_ref() {
+                                onevent
+                                    .call2(
+                                        &JsValue::NULL,
+                                        &JsValue::from_serde(&id).unwrap(),
+                                        &JsValue::from_serde(&payload).unwrap(),
+                                    )
+                                    .unwrap();
+                            }
+                        }
+                        ServerSentMessage::Joined { id } => {
+                            if let Some(onjoined) = cloned_self.onjoined.lock().unwrap().as_ref() {
+                                onjoined
+                                    .call1(&JsValue::NULL, &JsValue::from_serde(&id).unwrap())
+                                    .unwrap();
+                            }
+                        }
+                        ServerSentMessage::Left { id } => {
+                            if let Some(onleft) = cloned_self.onleft.lock().unwrap().as_ref() {
+                                onleft
+                                    .call1(&JsValue::NULL, &JsValue::from_serde(&id).unwrap())
+                                    .unwrap();
+                            }
+                        }
+                        ServerSentMessage::Kicked { id, reason } => {
+                            if let Some(onkicked) = cloned_self.onkicked.lock().unwrap().as_ref() {
+                                onkicked
+                                    .call2(
+                                        &JsValue::NULL,
+                                        &JsValue::from_serde(&id).unwrap(),
+                                        &JsValue::from_serde(&reason).unwrap(),
+                                    )
+                                    .unwrap();
+                            }
+                        }
+                        ServerSentMessage::Promoted { id } => {
+                            if let Some(onpromoted) =
+                                cloned_self.onpromoted.lock().unwrap().as_ref()
+                            {
+                                onpromoted
+                                    .call1(&JsValue::NULL, &JsValue::from_serde(&id).unwrap())
+                                    .unwrap();
+                            }
+                        }
+                        ServerSentMessage::LobbyClosed { id } => {
+                            if let Some(onclosed) = cloned_self.onclosed.lock().unwrap().as_ref() {
+                                onclosed
+                                    .call1(&JsValue::NULL, &JsValue::from_serde(&id).unwrap())
+                                    .unwrap();
+                            }
+                        }
+                        ServerSentMessage::QueuePosition {
+                            mode,
+                            position,
+                            queued,
+                        } => {
+                            if let Some(onqueue) = cloned_self.onqueue.lock().unwrap().as_ref() {
+                                onqueue
+                                    .call3(
+                                        &JsValue::NULL,
+                                        &JsValue::from_serde(&mode).unwrap(),
+                                        &JsValue::from_serde(&position).unwrap(),
+                                        &JsValue::from_serde(&queued).unwrap(),
+                                    )
+                                    .unwrap();
+                            }
+                        }
+                        ServerSentMessage::MatchFound { id, mode } => {
+                            if let Some(onmatch) = cloned_self.onmatch.lock().unwrap().as_ref() {
+                                onmatch
+                                    .call2(
+                                        &JsValue::NULL,
+                                        &JsValue::from_serde(&id).unwrap(),
+                                        &JsValue::from_serde(&mode).unwrap(),
+                                    )
+                                    .unwrap();
+                            }
+                        }
                     },
                     ServerMessage::ReplyTo(message_id, msg) => {
                         let callback = {